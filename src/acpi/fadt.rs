@@ -0,0 +1,213 @@
+// Index:
+// Imports           30
+// GenericAddress    37
+// Fadt (raw)        70
+// FadtInfo          123
+// init()            140
+// SLP_TYPa parsing  178
+//
+//
+// The FADT (Fixed ACPI Description Table, signature "FACP") describes the fixed hardware
+// registers of the power management model. For shutdown and reboot we need:
+//
+// Field            Description
+// SMI_CMD          I/O port used to hand the hardware over from SMM to ACPI mode
+// ACPI_ENABLE      value written to SMI_CMD to switch to ACPI mode
+// PM1a_CNT_BLK     PM1a control register, writing SLP_TYPa | SLP_EN puts the system to sleep
+// PM1b_CNT_BLK     optional second control register
+// RESET_REG        Generic Address of the reset register (ACPI 2.0+, only if flags bit 10)
+// RESET_VALUE      value written to RESET_REG to reset the system
+// CENTURY          CMOS RTC index of the century register (0 if not present)
+//
+// The sleep type for S5 (soft off) isn't in the FADT, it's the \_S5 package in the AML of the DSDT.
+// Instead of implementing a full AML interpreter we search the DSDT bytecode for the "_S5_"
+// name and decode the package by hand:
+//
+// NameOp(0x08) [\] "_S5_" PackageOp(0x12) PkgLength NumElements SLP_TYPa SLP_TYPb ...
+//
+// where each SLP_TYP is either ZeroOp(0x00), OneOp(0x01) or BytePrefix(0x0A) followed by a byte.
+
+use core::mem;
+use conquer_once::spin::OnceCell;
+use x86_64::PhysAddr;
+use super::{AcpiError, SdtHeader, find_table, table_at};
+
+static FADT: OnceCell<FadtInfo> = OnceCell::uninit();
+
+// Generic Address Structure, describes a register in one of the address spaces below
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct GenericAddress {
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpace {
+    SystemMemory,
+    SystemIo,
+    PciConfig,
+    Other(u8),
+}
+impl GenericAddress {
+    pub fn space(&self) -> AddressSpace {
+        match self.address_space {
+            0 => AddressSpace::SystemMemory,
+            1 => AddressSpace::SystemIo,
+            2 => AddressSpace::PciConfig,
+            other => AddressSpace::Other(other),
+        }
+    }
+}
+
+// Flags field
+const RESET_REG_SUP: u32 = 1 << 10;
+
+// Everything up to X_DSDT. Fields after `flags` only exist in ACPI 2.0+ tables, so check
+// `header.length` before reading them.
+#[allow(dead_code)]
+#[repr(C, packed)]
+struct Fadt {
+    header: SdtHeader,
+    firmware_ctrl: u32,
+    dsdt: u32,
+    _reserved: u8,
+    preferred_pm_profile: u8,
+    sci_interrupt: u16,
+    smi_command: u32,
+    acpi_enable: u8,
+    acpi_disable: u8,
+    s4bios_req: u8,
+    pstate_control: u8,
+    pm1a_event_block: u32,
+    pm1b_event_block: u32,
+    pm1a_control_block: u32,
+    pm1b_control_block: u32,
+    pm2_control_block: u32,
+    pm_timer_block: u32,
+    gpe0_block: u32,
+    gpe1_block: u32,
+    pm1_event_length: u8,
+    pm1_control_length: u8,
+    pm2_control_length: u8,
+    pm_timer_length: u8,
+    gpe0_length: u8,
+    gpe1_length: u8,
+    gpe1_base: u8,
+    c_state_control: u8,
+    worst_c2_latency: u16,
+    worst_c3_latency: u16,
+    flush_size: u16,
+    flush_stride: u16,
+    duty_offset: u8,
+    duty_width: u8,
+    day_alarm: u8,
+    month_alarm: u8,
+    century: u8,
+    boot_architecture_flags: u16,
+    _reserved2: u8,
+    flags: u32,
+    // ACPI 2.0+
+    reset_register: GenericAddress,
+    reset_value: u8,
+    arm_boot_architecture_flags: u16,
+    minor_version: u8,
+    x_firmware_control: u64,
+    x_dsdt: u64,
+}
+
+// The parts of the FADT the rest of the kernel cares about
+#[derive(Debug, Clone, Copy)]
+pub struct FadtInfo {
+    pub smi_command: u16,
+    pub acpi_enable: u8,
+    pub pm1a_control_block: u16,
+    pub pm1b_control_block: u16,
+    // SLP_TYPa and SLP_TYPb of the \_S5 package
+    pub s5_sleep_types: Option<(u16, u16)>,
+    pub reset: Option<(GenericAddress, u8)>,
+    pub century: u8,
+}
+
+pub fn get() -> Option<&'static FadtInfo> {
+    FADT.try_get().ok()
+}
+
+// Parses the FADT and the \_S5 package of the DSDT. Must be called after `acpi::init`.
+pub fn init() -> Result<(), AcpiError> {
+    let header = find_table(b"FACP").ok_or(AcpiError::TableNotFound("FACP"))?;
+    let length = header.length as usize;
+    let fadt = unsafe { &*(header as *const SdtHeader as *const Fadt) };
+
+    let has = |offset: usize, size: usize| offset + size <= length;
+
+    let reset = if has(mem::offset_of!(Fadt, reset_value), 1) && fadt.flags & RESET_REG_SUP != 0 {
+        Some((fadt.reset_register, fadt.reset_value))
+    } else {
+        None
+    };
+
+    let dsdt_address = if has(mem::offset_of!(Fadt, x_dsdt), mem::size_of::<u64>()) && fadt.x_dsdt != 0 {
+        fadt.x_dsdt
+    } else {
+        fadt.dsdt as u64
+    };
+    let s5_sleep_types = if dsdt_address != 0 {
+        let dsdt = unsafe { table_at(PhysAddr::new(dsdt_address)) };
+        find_s5_sleep_types(dsdt.as_bytes())
+    } else {
+        None
+    };
+
+    let info = FadtInfo {
+        smi_command: fadt.smi_command as u16,
+        acpi_enable: fadt.acpi_enable,
+        pm1a_control_block: fadt.pm1a_control_block as u16,
+        pm1b_control_block: fadt.pm1b_control_block as u16,
+        s5_sleep_types,
+        reset,
+        century: fadt.century,
+    };
+    FADT.try_init_once(|| info).expect("fadt::init should only be called once");
+    Ok(())
+}
+
+// SLP_TYPa parsing
+
+fn find_s5_sleep_types(dsdt: &[u8]) -> Option<(u16, u16)> {
+    let body = &dsdt[mem::size_of::<SdtHeader>()..];
+    let position = body.windows(4).position(|window| window == b"_S5_")?;
+
+    // must be a NameOp, either "NameOp _S5_" or "NameOp \_S5_"
+    let is_name = (position >= 1 && body[position - 1] == 0x08)
+        || (position >= 2 && body[position - 2] == 0x08 && body[position - 1] == b'\\');
+    if !is_name {
+        return None;
+    }
+
+    let mut bytes = body[position + 4..].iter().copied();
+    if bytes.next()? != 0x12 {
+        return None; // not a PackageOp
+    }
+
+    // PkgLength, bits 6-7 of the lead byte are the number of bytes that follow
+    let lead = bytes.next()?;
+    for _ in 0..(lead >> 6) {
+        bytes.next()?;
+    }
+    let _num_elements = bytes.next()?;
+
+    let mut sleep_type = || -> Option<u16> {
+        match bytes.next()? {
+            0x0A => bytes.next().map(u16::from), // BytePrefix
+            value @ (0x00 | 0x01) => Some(value as u16), // ZeroOp/OneOp
+            _ => None,
+        }
+    };
+    let slp_typa = sleep_type()?;
+    let slp_typb = sleep_type().unwrap_or(0);
+    Some((slp_typa, slp_typb))
+}
@@ -0,0 +1,47 @@
+// The HPET table (signature "HPET") tells us where the registers of the High Precision Event Timer
+// are. The address is a Generic Address Structure, the HPET is always memory mapped.
+
+use core::mem;
+use x86_64::PhysAddr;
+use super::{fadt::{AddressSpace, GenericAddress}, find_table, AcpiError, SdtHeader};
+
+#[allow(dead_code)]
+#[repr(C, packed)]
+struct HpetTable {
+    header: SdtHeader,
+    hardware_revision: u8,
+    // bits 0-4: number of comparators - 1, bit 5: 64 bit counter, bit 7: legacy replacement capable
+    comparator_info: u8,
+    pci_vendor_id: u16,
+    address: GenericAddress,
+    hpet_number: u8,
+    minimum_tick: u16,
+    page_protection: u8,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HpetInfo {
+    pub base_address: PhysAddr,
+    pub hpet_number: u8,
+    // minimum clock ticks for periodic mode without losing interrupts
+    pub minimum_tick: u16,
+}
+
+pub fn find() -> Result<HpetInfo, AcpiError> {
+    let header = find_table(b"HPET").ok_or(AcpiError::TableNotFound("HPET"))?;
+    if (header.length as usize) < mem::size_of::<HpetTable>() {
+        return Err(AcpiError::TableNotFound("HPET"));
+    }
+    let table = unsafe { &*(header as *const SdtHeader as *const HpetTable) };
+
+    let address = table.address;
+    if address.space() != AddressSpace::SystemMemory {
+        return Err(AcpiError::TableNotFound("HPET"));
+    }
+
+    Ok(HpetInfo {
+        base_address: PhysAddr::new(address.address),
+        hpet_number: table.hpet_number,
+        minimum_tick: table.minimum_tick,
+    })
+}
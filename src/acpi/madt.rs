@@ -0,0 +1,102 @@
+// The MADT (Multiple APIC Description Table, signature "APIC") lists the interrupt controllers of
+// the machine: one local APIC per processor, the I/O APICs and how the legacy IRQs are wired to
+// them. After the fixed part comes a list of variable length entries, each starting with its type
+// and length:
+//
+// Type  Entry                        Fields we use
+// 0     Processor Local APIC         ACPI processor id (u8), APIC id (u8), flags (u32)
+// 5     Local APIC Address Override  64 bit address of the local APICs
+// 9     Processor Local x2APIC       x2APIC id (u32), flags (u32), ACPI processor id (u32)
+//
+// Bit 0 of the processor flags means the processor is enabled, bit 1 that it can be enabled later.
+
+use core::{mem, ptr, slice};
+use x86_64::PhysAddr;
+use super::{find_table, AcpiError, SdtHeader};
+
+#[allow(dead_code)]
+#[repr(C, packed)]
+struct Madt {
+    header: SdtHeader,
+    local_apic_address: u32,
+    // bit 0: the machine also has the legacy 8259 PICs
+    flags: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MadtInfo {
+    pub local_apic_address: PhysAddr,
+    pub pic_compatible: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Processor {
+    pub processor_id: u32,
+    pub apic_id: u32,
+    pub enabled: bool,
+    pub online_capable: bool,
+}
+
+fn madt() -> Result<&'static Madt, AcpiError> {
+    let header = find_table(b"APIC").ok_or(AcpiError::TableNotFound("APIC"))?;
+    if (header.length as usize) < mem::size_of::<Madt>() {
+        return Err(AcpiError::TableNotFound("APIC"));
+    }
+    Ok(unsafe { &*(header as *const SdtHeader as *const Madt) })
+}
+
+// The entries as (type, body without type and length)
+fn entries(madt: &'static Madt) -> impl Iterator<Item = (u8, &'static [u8])> {
+    let bytes = unsafe { slice::from_raw_parts(madt as *const Madt as *const u8, madt.header.length as usize) };
+    let mut rest = &bytes[mem::size_of::<Madt>()..];
+
+    core::iter::from_fn(move || {
+        let (&kind, &length) = (rest.first()?, rest.get(1)?);
+        // a broken length would loop forever or run past the table
+        if length < 2 || length as usize > rest.len() {
+            return None;
+        }
+        let body = &rest[2..length as usize];
+        rest = &rest[length as usize..];
+        Some((kind, body))
+    })
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    let bytes = bytes.get(offset..offset + 4)?;
+    Some(unsafe { ptr::read_unaligned(bytes.as_ptr() as *const u32) })
+}
+
+pub fn find() -> Result<MadtInfo, AcpiError> {
+    let madt = madt()?;
+
+    let mut local_apic_address = madt.local_apic_address as u64;
+    for (kind, body) in entries(madt) {
+        if kind == 5 && body.len() >= 10 {
+            local_apic_address = unsafe { ptr::read_unaligned(body[2..].as_ptr() as *const u64) };
+        }
+    }
+
+    Ok(MadtInfo {
+        local_apic_address: PhysAddr::new(local_apic_address),
+        pic_compatible: madt.flags & 1 != 0,
+    })
+}
+
+// All processors in the order of the table, the first one is usually the bootstrap processor.
+// Empty without a MADT.
+pub fn processors() -> impl Iterator<Item = Processor> {
+    madt().ok().into_iter().flat_map(entries).filter_map(|(kind, body)| {
+        let (processor_id, apic_id, flags) = match kind {
+            0 => (*body.first()? as u32, *body.get(1)? as u32, read_u32(body, 2)?),
+            9 => (read_u32(body, 10)?, read_u32(body, 2)?, read_u32(body, 6)?),
+            _ => return None,
+        };
+        Some(Processor {
+            processor_id,
+            apic_id,
+            enabled: flags & 1 != 0,
+            online_capable: flags & 2 != 0,
+        })
+    })
+}
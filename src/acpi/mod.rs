@@ -0,0 +1,185 @@
+// Index:
+// Imports       29
+// AcpiError     41
+// Rsdp          49
+// SdtHeader     66
+// init()        95
+// find_table()  143
+// RSDP search   159
+//
+//
+// ACPI (Advanced Configuration and Power Interface) is how the firmware describes the machine to
+// the operating system. Everything starts at the RSDP (Root System Description Pointer), which
+// the BIOS places either in the first KiB of the EBDA (Extended BIOS Data Area) or somewhere in
+// the read-only BIOS area between 0xE0000 and 0xFFFFF, always on a 16 byte boundary.
+//
+// The RSDP points to the RSDT (ACPI 1.0, 32 bit pointers) or the XSDT (ACPI 2.0+, 64 bit
+// pointers). Both are a list of pointers to the other tables, every one of them starting with the
+// same SdtHeader. The tables we care about are identified by their 4 byte signature:
+//
+// Signature  Name                               Used for
+// FACP       Fixed ACPI Description Table       power management registers, reset register
+// DSDT       Differentiated System Desc. Table  AML code, contains the \_S5 sleep package
+// HPET       High Precision Event Timer Table   base address of the HPET registers
+// APIC       Multiple APIC Description Table    local APIC address, list of processors
+//
+// All tables live in physical memory, the bootloader maps all of it at the physical memory
+// offset, so we can read them through `memory::memory::phys_to_virt`.
+
+use core::{mem, ptr, slice};
+use conquer_once::spin::OnceCell;
+use x86_64::{PhysAddr, VirtAddr};
+use crate::memory::memory::phys_to_virt;
+
+pub mod fadt;
+pub mod hpet;
+pub mod madt;
+
+static ROOT_TABLE: OnceCell<RootTable> = OnceCell::uninit();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    RsdpNotFound,
+    InvalidChecksum(&'static str),
+    TableNotFound(&'static str),
+}
+
+// ACPI 2.0+ RSDP, the first 20 bytes are the ACPI 1.0 RSDP
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // only valid if revision >= 2
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    _reserved: [u8; 3],
+}
+const RSDP_V1_LENGTH: usize = 20;
+
+// Header shared by all System Description Tables
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+impl SdtHeader {
+    // The whole table, header included, as bytes
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self as *const Self as *const u8, self.length as usize) }
+    }
+
+    fn is_valid(&self) -> bool {
+        checksum(self.as_bytes()) == 0
+    }
+}
+
+// RSDT entries are 32 bits wide, XSDT entries 64 bits
+struct RootTable {
+    header: &'static SdtHeader,
+    entry_size: usize,
+}
+
+// Locates the RSDP and validates the RSDT/XSDT. Must be called after `memory::memory::init`.
+pub fn init() -> Result<(), AcpiError> {
+    let rsdp = find_rsdp().ok_or(AcpiError::RsdpNotFound)?;
+
+    let (address, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        let bytes = unsafe { slice::from_raw_parts(rsdp as *const Rsdp as *const u8, rsdp.length as usize) };
+        if checksum(bytes) != 0 {
+            return Err(AcpiError::InvalidChecksum("RSDP"));
+        }
+        (rsdp.xsdt_address, mem::size_of::<u64>())
+    } else {
+        (rsdp.rsdt_address as u64, mem::size_of::<u32>())
+    };
+
+    let header = unsafe { table_at(PhysAddr::new(address)) };
+    if !header.is_valid() {
+        return Err(AcpiError::InvalidChecksum("RSDT"));
+    }
+
+    ROOT_TABLE.try_init_once(|| RootTable { header, entry_size }).expect("acpi::init should only be called once");
+    Ok(())
+}
+
+// Physical addresses of all tables listed in the RSDT/XSDT
+fn table_addresses() -> impl Iterator<Item = PhysAddr> {
+    let root = ROOT_TABLE.try_get().ok();
+    let (start, count, entry_size) = match root {
+        Some(root) => {
+            let start = root.header as *const SdtHeader as usize + mem::size_of::<SdtHeader>();
+            let count = (root.header.length as usize - mem::size_of::<SdtHeader>()) / root.entry_size;
+            (start, count, root.entry_size)
+        }
+        None => (0, 0, 1),
+    };
+
+    (0..count).map(move |i| {
+        let entry = (start + i * entry_size) as *const u8;
+        let address = unsafe {
+            if entry_size == mem::size_of::<u64>() {
+                ptr::read_unaligned(entry as *const u64)
+            } else {
+                ptr::read_unaligned(entry as *const u32) as u64
+            }
+        };
+        PhysAddr::new(address)
+    })
+}
+
+// Returns the first table with the given signature, if its checksum is valid
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static SdtHeader> {
+    table_addresses()
+        .map(|address| unsafe { table_at(address) })
+        .find(|table| &table.signature == signature && table.is_valid())
+}
+
+// The caller must make sure there is a valid table at `address`
+pub(crate) unsafe fn table_at(address: PhysAddr) -> &'static SdtHeader {
+    &*phys_to_virt(address).as_ptr::<SdtHeader>()
+}
+
+// All bytes of a valid table (or RSDP) add up to 0
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+// RSDP search
+
+fn find_rsdp() -> Option<&'static Rsdp> {
+    // The real mode segment of the EBDA is stored at 0x40E
+    let ebda_segment = unsafe { ptr::read_volatile(phys_to_virt(PhysAddr::new(0x40E)).as_ptr::<u16>()) };
+    let ebda = (ebda_segment as u64) << 4;
+
+    let ebda_area = if ebda != 0 { Some(ebda..ebda + 1024) } else { None };
+    ebda_area
+        .into_iter()
+        .chain(core::iter::once(0xE0000..0x100000))
+        .find_map(|area| search_rsdp(area.start, area.end))
+}
+
+fn search_rsdp(start: u64, end: u64) -> Option<&'static Rsdp> {
+    (start..end).step_by(16).find_map(|address| {
+        let virtual_address: VirtAddr = phys_to_virt(PhysAddr::new(address));
+        let rsdp = unsafe { &*virtual_address.as_ptr::<Rsdp>() };
+        let bytes = unsafe { slice::from_raw_parts(virtual_address.as_ptr::<u8>(), RSDP_V1_LENGTH) };
+
+        if &rsdp.signature == b"RSD PTR " && checksum(bytes) == 0 {
+            Some(rsdp)
+        } else {
+            None
+        }
+    })
+}
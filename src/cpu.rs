@@ -0,0 +1,229 @@
+// CPU identification and features through CPUID
+//
+// CPUID takes a leaf number in EAX and returns information in EAX, EBX, ECX and EDX. Leaf 0 and
+// 0x80000000 return the highest basic and extended leaf, anything above those returns garbage.
+//
+// Leaf           Returns
+// 0x0            highest basic leaf (EAX), vendor string (EBX, EDX, ECX)
+// 0x1            family, model and stepping (EAX), feature flags (ECX, EDX)
+// 0x7            extended feature flags (EBX, ECX, EDX), subleaf 0 in ECX
+// 0x80000000     highest extended leaf (EAX)
+// 0x80000001     extended feature flags (ECX, EDX)
+// 0x80000002-4   brand string, 16 bytes per leaf
+// 0x80000007     power management flags (EDX)
+//
+// The features we look at:
+//
+// Leaf        Register  Bit  Feature
+// 0x1         EDX       0    x87 FPU
+// 0x1         EDX       4    TSC
+// 0x1         EDX       9    local APIC
+// 0x1         EDX       24   FXSAVE/FXRSTOR
+// 0x1         EDX       25   SSE
+// 0x1         EDX       26   SSE2
+// 0x1         ECX       17   PCID (process context identifiers)
+// 0x1         ECX       21   x2APIC
+// 0x1         ECX       26   XSAVE
+// 0x1         ECX       28   AVX
+// 0x1         ECX       30   RDRAND
+// 0x7         EBX       7    SMEP (supervisor mode execution prevention)
+// 0x7         EBX       18   RDSEED
+// 0x7         EBX       20   SMAP (supervisor mode access prevention)
+// 0x80000001  EDX       20   NX (no execute)
+// 0x80000001  EDX       26   1 GiB pages
+// 0x80000007  EDX       8    invariant TSC
+//
+// All CPUs of the machine are assumed to be the same, the BSP is asked once and the answer kept.
+
+use core::{arch::x86_64::{CpuidResult, __cpuid, __cpuid_count}, str};
+use lazy_static::lazy_static;
+
+lazy_static! {
+    static ref INFO: CpuInfo = CpuInfo::read();
+}
+
+// The registers the features are in, index into CpuInfo::registers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Register {
+    Leaf1Ecx,
+    Leaf1Edx,
+    Leaf7Ebx,
+    Extended1Edx,
+    Extended7Edx,
+}
+const REGISTERS: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Feature {
+    Fpu,
+    Tsc,
+    Apic,
+    Fxsr,
+    Sse,
+    Sse2,
+    Pcid,
+    X2Apic,
+    Xsave,
+    Avx,
+    Rdrand,
+    Smep,
+    Rdseed,
+    Smap,
+    Nx,
+    Pages1G,
+    InvariantTsc,
+}
+impl Feature {
+    pub const ALL: [Feature; 17] = [
+        Feature::Fpu,
+        Feature::Tsc,
+        Feature::Apic,
+        Feature::Fxsr,
+        Feature::Sse,
+        Feature::Sse2,
+        Feature::Pcid,
+        Feature::X2Apic,
+        Feature::Xsave,
+        Feature::Avx,
+        Feature::Rdrand,
+        Feature::Smep,
+        Feature::Rdseed,
+        Feature::Smap,
+        Feature::Nx,
+        Feature::Pages1G,
+        Feature::InvariantTsc,
+    ];
+
+    // The names Linux uses in /proc/cpuinfo
+    pub fn name(&self) -> &'static str {
+        match self {
+            Feature::Fpu => "fpu",
+            Feature::Tsc => "tsc",
+            Feature::Apic => "apic",
+            Feature::Fxsr => "fxsr",
+            Feature::Sse => "sse",
+            Feature::Sse2 => "sse2",
+            Feature::Pcid => "pcid",
+            Feature::X2Apic => "x2apic",
+            Feature::Xsave => "xsave",
+            Feature::Avx => "avx",
+            Feature::Rdrand => "rdrand",
+            Feature::Smep => "smep",
+            Feature::Rdseed => "rdseed",
+            Feature::Smap => "smap",
+            Feature::Nx => "nx",
+            Feature::Pages1G => "pdpe1gb",
+            Feature::InvariantTsc => "constant_tsc",
+        }
+    }
+
+    fn location(&self) -> (Register, u32) {
+        match self {
+            Feature::Fpu => (Register::Leaf1Edx, 0),
+            Feature::Tsc => (Register::Leaf1Edx, 4),
+            Feature::Apic => (Register::Leaf1Edx, 9),
+            Feature::Fxsr => (Register::Leaf1Edx, 24),
+            Feature::Sse => (Register::Leaf1Edx, 25),
+            Feature::Sse2 => (Register::Leaf1Edx, 26),
+            Feature::Pcid => (Register::Leaf1Ecx, 17),
+            Feature::X2Apic => (Register::Leaf1Ecx, 21),
+            Feature::Xsave => (Register::Leaf1Ecx, 26),
+            Feature::Avx => (Register::Leaf1Ecx, 28),
+            Feature::Rdrand => (Register::Leaf1Ecx, 30),
+            Feature::Smep => (Register::Leaf7Ebx, 7),
+            Feature::Rdseed => (Register::Leaf7Ebx, 18),
+            Feature::Smap => (Register::Leaf7Ebx, 20),
+            Feature::Nx => (Register::Extended1Edx, 20),
+            Feature::Pages1G => (Register::Extended1Edx, 26),
+            Feature::InvariantTsc => (Register::Extended7Edx, 8),
+        }
+    }
+}
+
+pub struct CpuInfo {
+    vendor: [u8; 12],
+    brand: [u8; 48],
+    pub family: u32,
+    pub model: u32,
+    pub stepping: u32,
+    registers: [u32; REGISTERS],
+}
+impl CpuInfo {
+    // __cpuid is only a safe function on newer toolchains
+    #[allow(unused_unsafe)]
+    fn read() -> CpuInfo {
+        let cpuid = |leaf: u32| unsafe { __cpuid(leaf) };
+        // a leaf above the highest one reads as zeroes
+        let zero = CpuidResult { eax: 0, ebx: 0, ecx: 0, edx: 0 };
+
+        let leaf0 = cpuid(0);
+        let max_leaf = leaf0.eax;
+        let max_extended_leaf = cpuid(0x8000_0000).eax;
+        let leaf1 = if max_leaf >= 1 { cpuid(1) } else { zero };
+        let leaf7 = if max_leaf >= 7 { unsafe { __cpuid_count(7, 0) } } else { zero };
+        let extended = |leaf: u32| if max_extended_leaf >= leaf { cpuid(leaf) } else { zero };
+
+        let mut vendor = [0; 12];
+        for (chunk, register) in vendor.chunks_mut(4).zip([leaf0.ebx, leaf0.edx, leaf0.ecx]) {
+            chunk.copy_from_slice(&register.to_le_bytes());
+        }
+
+        let mut brand = [0; 48];
+        for (chunk, leaf) in brand.chunks_mut(16).zip(0x8000_0002..=0x8000_0004) {
+            let result = extended(leaf);
+            for (bytes, register) in chunk.chunks_mut(4).zip([result.eax, result.ebx, result.ecx, result.edx]) {
+                bytes.copy_from_slice(&register.to_le_bytes());
+            }
+        }
+
+        // the extended family and model only count for some base families
+        let base_family = (leaf1.eax >> 8) & 0xF;
+        let base_model = (leaf1.eax >> 4) & 0xF;
+        let family = if base_family == 0xF { base_family + ((leaf1.eax >> 20) & 0xFF) } else { base_family };
+        let model = if base_family == 0x6 || base_family == 0xF {
+            base_model | ((leaf1.eax >> 12) & 0xF0)
+        } else {
+            base_model
+        };
+
+        CpuInfo {
+            vendor,
+            brand,
+            family,
+            model,
+            stepping: leaf1.eax & 0xF,
+            registers: [leaf1.ecx, leaf1.edx, leaf7.ebx, extended(0x8000_0001).edx, extended(0x8000_0007).edx],
+        }
+    }
+
+    // e.g. "GenuineIntel" or "AuthenticAMD"
+    pub fn vendor(&self) -> &str {
+        str::from_utf8(&self.vendor).unwrap_or("unknown")
+    }
+
+    // Empty if the CPU doesn't have a brand string
+    pub fn brand(&self) -> &str {
+        str::from_utf8(&self.brand).unwrap_or("").trim_matches(|c: char| c == '\0' || c == ' ')
+    }
+
+    pub fn has(&self, feature: Feature) -> bool {
+        let (register, bit) = feature.location();
+        self.registers[register as usize] & (1 << bit) != 0
+    }
+}
+
+pub fn info() -> &'static CpuInfo {
+    &INFO
+}
+
+pub fn has(feature: Feature) -> bool {
+    INFO.has(feature)
+}
+
+// Tests
+#[test_case]
+fn test_cpuid_basics() {
+    // every x86_64 CPU has these, long mode needs them
+    assert!(has(Feature::Fpu) && has(Feature::Tsc) && has(Feature::Sse2));
+    assert_eq!(info().vendor().len(), 12);
+}
@@ -1,5 +1,6 @@
 pub mod vga_buffer;
 pub mod serial;
+pub mod ps2;
 
 
 // Macros
@@ -41,17 +42,15 @@ fn test_println_many() {
 #[test_case]
 fn test_println_output() {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
 
     let s = "Some test string that fits in 1 line";
-    interrupts::without_interrupts(|| {
-        let mut writer = vga_buffer::WRITER.lock();
-        writeln!(writer, "\n{}", s).expect("writeln failed");
-        for (i, c) in s.chars().enumerate() {
-            // Since the println prints to the last screen line and then immediately appends a newline,
-            // the string should appear on line BUFFER_HEIGHT - 2
-            let screen_char = writer.buffer.chars[vga_buffer::writer::BUFFER_HEIGHT - 2][i].read();
-            assert_eq!(char::from(screen_char.ascii_character), c);
-        }
-    });
+    // the lock keeps the timer interrupt from printing in between
+    let mut writer = vga_buffer::WRITER.lock();
+    writeln!(writer, "\n{}", s).expect("writeln failed");
+    for (i, c) in s.chars().enumerate() {
+        // Since the println prints to the last screen line and then immediately appends a newline,
+        // the string should appear on line BUFFER_HEIGHT - 2
+        let screen_char = writer.buffer.chars[vga_buffer::writer::BUFFER_HEIGHT - 2][i].read();
+        assert_eq!(char::from(screen_char.ascii_character), c);
+    }
 }
@@ -0,0 +1,520 @@
+// Index:
+// Imports            47
+// Ps2Error           79
+// Controller         128
+// init()             266
+// Translation        337
+// Keyboard commands  357
+//
+// The 8042 PS/2 controller connects the keyboard (first port) and the mouse (second port).
+//
+// Port  Access  Description
+// 0x60  R/W     data: bytes from and to the devices, and the responses to controller commands
+// 0x64  R       status: bit 0 output buffer full (data to read), bit 1 input buffer full (don't
+//               write yet), bit 5 the byte in the output buffer is from the second port
+// 0x64  W       command to the controller
+//
+// Command  Description
+// 0x20     read the configuration byte
+// 0x60     write the configuration byte
+// 0xA7     disable the second port
+// 0xA8     enable the second port
+// 0xA9     test the second port, 0x00 means passed
+// 0xAA     controller self-test, 0x55 means passed
+// 0xAB     test the first port, 0x00 means passed
+// 0xAD     disable the first port
+// 0xAE     enable the first port
+// 0xD4     send the next data byte to the second port
+//
+// Configuration bits: 0 first port IRQ, 1 second port IRQ, 4 first port clock disabled, 5 second
+// port clock disabled, 6 translation of scancode set 2 to set 1.
+//
+// Devices answer commands with 0xFA (ACK) or 0xFE (resend). During `init` the port IRQs are off
+// and we poll for the answers. After that the keyboard IRQ handler gets every byte, so keyboard
+// commands are queued and driven by the answers the handler passes to `keyboard_response`. Those
+// run in IRQ handlers and never wait for the controller: a byte it has no room for yet is retried
+// on the next timer tick.
+//
+// Timeouts are measured time, a keyboard takes up to 500ms for the self-test after a reset. While
+// `init` runs with interrupts disabled a PIT based clock stands still, so without a TSC or HPET
+// clocksource we count status reads instead, each one an ISA bus access of about a microsecond.
+//
+// Keyboard command  Data byte
+// 0xED set LEDs     bit 0 Scroll Lock, bit 1 Num Lock, bit 2 Caps Lock
+// 0xF3 typematic    bits 0-4 repeat rate (0 is 30/s, 31 is 2/s), bits 5-6 delay (250ms * (n + 1))
+// 0xFF reset        answers 0xAA after the self-test
+
+use core::time::Duration;
+use x86_64::instructions::{interrupts, port::{Port, PortReadOnly, PortWriteOnly}};
+use crate::{
+    memory::irq::{self, IrqAction, IrqResult},
+    sync::IrqMutex,
+    task::keymap::{self, ScancodeSet},
+    time::{self, ClockSource, Instant},
+};
+
+const OUTPUT_FULL: u8 = 1 << 0;
+const INPUT_FULL: u8 = 1 << 1;
+const SECOND_PORT_DATA: u8 = 1 << 5;
+
+const FIRST_PORT_IRQ: u8 = 1 << 0;
+const SECOND_PORT_IRQ: u8 = 1 << 1;
+const SECOND_PORT_CLOCK_DISABLED: u8 = 1 << 5;
+const TRANSLATION: u8 = 1 << 6;
+
+const ACK: u8 = 0xFA;
+const RESEND: u8 = 0xFE;
+const MAX_RESENDS: u8 = 3;
+// How long the controller may take to make room for a byte or to answer
+const CONTROLLER_TIMEOUT: Duration = Duration::from_millis(10);
+// How long a device may take to answer a command, and to finish its self-test after a reset
+const DEVICE_TIMEOUT: Duration = Duration::from_millis(100);
+const RESET_TIMEOUT: Duration = Duration::from_secs(1);
+
+static CONTROLLER: IrqMutex<Controller> = IrqMutex::new(Controller::new());
+static INFO: IrqMutex<Option<Ps2Info>> = IrqMutex::new(None);
+static KEYBOARD_COMMANDS: IrqMutex<CommandQueue> = IrqMutex::new(CommandQueue::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Error {
+    Timeout,
+    SelfTestFailed(u8),
+    PortTestFailed(u8),
+    // the device answered something other than ACK
+    NoAck(u8),
+    ResetFailed(u8),
+    NotInitialized,
+}
+
+// What `init` found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ps2Info {
+    pub dual_channel: bool,
+    pub keyboard: bool,
+    pub mouse_port: bool,
+    pub translation: bool,
+}
+
+pub fn info() -> Option<Ps2Info> {
+    *INFO.lock()
+}
+
+// When to give up waiting, checked once per status read
+enum Deadline {
+    At(Instant),
+    // status reads left, for when the clock stands still
+    Reads(u64),
+}
+impl Deadline {
+    fn after(timeout: Duration) -> Deadline {
+        if time::clocksource() == ClockSource::Pit && !interrupts::are_enabled() {
+            Deadline::Reads(timeout.as_micros() as u64)
+        } else {
+            Deadline::At(Instant::now() + timeout)
+        }
+    }
+
+    fn passed(&mut self) -> bool {
+        match self {
+            Deadline::At(at) => Instant::now() >= *at,
+            Deadline::Reads(left) => {
+                *left = left.saturating_sub(1);
+                *left == 0
+            }
+        }
+    }
+}
+
+// Controller
+
+struct Controller {
+    data: Port<u8>,
+    status: PortReadOnly<u8>,
+    command: PortWriteOnly<u8>,
+}
+impl Controller {
+    const fn new() -> Self {
+        Controller {
+            data: Port::new(0x60),
+            status: PortReadOnly::new(0x64),
+            command: PortWriteOnly::new(0x64),
+        }
+    }
+
+    fn status(&mut self) -> u8 {
+        unsafe { self.status.read() }
+    }
+
+    fn wait_input_empty(&mut self) -> Result<(), Ps2Error> {
+        let mut deadline = Deadline::after(CONTROLLER_TIMEOUT);
+        while self.status() & INPUT_FULL != 0 {
+            if deadline.passed() {
+                return Err(Ps2Error::Timeout);
+            }
+        }
+        Ok(())
+    }
+
+    fn read(&mut self) -> Result<u8, Ps2Error> {
+        self.read_within(CONTROLLER_TIMEOUT)
+    }
+
+    fn read_within(&mut self, timeout: Duration) -> Result<u8, Ps2Error> {
+        let mut deadline = Deadline::after(timeout);
+        while self.status() & OUTPUT_FULL == 0 {
+            if deadline.passed() {
+                return Err(Ps2Error::Timeout);
+            }
+        }
+        Ok(unsafe { self.data.read() })
+    }
+
+    fn command(&mut self, command: u8) -> Result<(), Ps2Error> {
+        self.wait_input_empty()?;
+        unsafe { self.command.write(command) };
+        Ok(())
+    }
+
+    fn command_with_response(&mut self, command: u8) -> Result<u8, Ps2Error> {
+        self.command(command)?;
+        self.read()
+    }
+
+    fn write(&mut self, data: u8) -> Result<(), Ps2Error> {
+        self.wait_input_empty()?;
+        unsafe { self.data.write(data) };
+        Ok(())
+    }
+
+    // Writes `data` only if the controller has room for it right now
+    fn try_write(&mut self, data: u8) -> bool {
+        if self.status() & INPUT_FULL != 0 {
+            return false;
+        }
+        unsafe { self.data.write(data) };
+        true
+    }
+
+    fn config(&mut self) -> Result<u8, Ps2Error> {
+        self.command_with_response(0x20)
+    }
+
+    fn set_config(&mut self, config: u8) -> Result<(), Ps2Error> {
+        self.command(0x60)?;
+        self.write(config)
+    }
+
+    fn flush(&mut self) {
+        for _ in 0..16 {
+            if self.status() & OUTPUT_FULL == 0 {
+                break;
+            }
+            unsafe { self.data.read() };
+        }
+    }
+
+    // Sends a byte to a device and waits for the ACK, resending if asked to
+    fn send(&mut self, second_port: bool, byte: u8) -> Result<(), Ps2Error> {
+        for _ in 0..=MAX_RESENDS {
+            if second_port {
+                self.command(0xD4)?;
+            }
+            self.write(byte)?;
+            match self.read_within(DEVICE_TIMEOUT)? {
+                ACK => return Ok(()),
+                RESEND => continue,
+                other => return Err(Ps2Error::NoAck(other)),
+            }
+        }
+        Err(Ps2Error::NoAck(RESEND))
+    }
+
+    fn reset_device(&mut self, second_port: bool) -> Result<(), Ps2Error> {
+        self.send(second_port, 0xFF)?;
+        match self.read_within(RESET_TIMEOUT)? {
+            0xAA => {}
+            other => return Err(Ps2Error::ResetFailed(other)),
+        }
+        // mice send their device id after the self-test
+        if second_port {
+            let _ = self.read_within(DEVICE_TIMEOUT);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Device {
+    Keyboard,
+    Mouse,
+}
+
+// Reads the byte waiting in the output buffer if it came from `device`. The keyboard and mouse IRQ
+// handlers use this, the IRQ might be stale or the byte might belong to the other device.
+pub fn pending(device: Device) -> Option<u8> {
+    let mut controller = CONTROLLER.lock();
+    let status = controller.status();
+    let from_mouse = status & SECOND_PORT_DATA != 0;
+    if status & OUTPUT_FULL == 0 || from_mouse != (device == Device::Mouse) {
+        return None;
+    }
+    Some(unsafe { controller.data.read() })
+}
+
+// Initializes the controller and resets the devices. Translation is enabled unless the keymap asks
+// for scancode set 2. Must be called before the keyboard and mouse IRQ handlers are registered.
+pub fn init() -> Result<Ps2Info, Ps2Error> {
+    let info = {
+        let mut controller = CONTROLLER.lock();
+
+        // disable the devices so they don't send anything while we set up the controller
+        controller.command(0xAD)?;
+        controller.command(0xA7)?;
+        controller.flush();
+
+        let mut config = controller.config()?;
+        config &= !(FIRST_PORT_IRQ | SECOND_PORT_IRQ | TRANSLATION);
+        controller.set_config(config)?;
+
+        match controller.command_with_response(0xAA)? {
+            0x55 => {}
+            other => return Err(Ps2Error::SelfTestFailed(other)),
+        }
+        // some controllers reset their configuration during the self-test
+        controller.set_config(config)?;
+
+        // if enabling the second port clears its clock disable bit, there is a second port
+        controller.command(0xA8)?;
+        let dual_channel = controller.config()? & SECOND_PORT_CLOCK_DISABLED == 0;
+        controller.command(0xA7)?;
+
+        match controller.command_with_response(0xAB)? {
+            0x00 => {}
+            other => return Err(Ps2Error::PortTestFailed(other)),
+        }
+        let mouse_port = dual_channel && controller.command_with_response(0xA9)? == 0x00;
+
+        controller.command(0xAE)?;
+        let keyboard = controller.reset_device(false).is_ok();
+        if mouse_port {
+            controller.command(0xA8)?;
+            // a missing mouse is fine, the port is still usable for hot-plugging in QEMU
+            let _ = controller.reset_device(true);
+        }
+        controller.flush();
+
+        let translation = keymap::scancode_set() == ScancodeSet::Set1;
+        config |= FIRST_PORT_IRQ;
+        if mouse_port {
+            config |= SECOND_PORT_IRQ;
+        }
+        if translation {
+            config |= TRANSLATION;
+        }
+        controller.set_config(config)?;
+
+        Ps2Info { dual_channel, keyboard, mouse_port, translation }
+    };
+
+    *INFO.lock() = Some(info);
+    if info.keyboard {
+        irq::register(irq::TIMER, &COMMAND_ACTION).expect("PS/2 command IRQ handler already registered");
+    }
+    Ok(info)
+}
+
+// Sends a byte to the second port and waits for the ACK. The mouse driver uses this during its
+// setup, before it registers its IRQ handler.
+pub fn send_to_second_port(byte: u8) -> Result<(), Ps2Error> {
+    CONTROLLER.lock().send(true, byte)
+}
+
+// Reads the next byte from the controller, waiting for a device to send it
+pub fn read_response() -> Result<u8, Ps2Error> {
+    CONTROLLER.lock().read_within(DEVICE_TIMEOUT)
+}
+
+// Translation
+
+// With translation the controller converts scancode set 2 to set 1 before we see it. The keymap
+// decoder is switched to match.
+pub fn set_translation(enabled: bool) -> Result<(), Ps2Error> {
+    let mut info = info().ok_or(Ps2Error::NotInitialized)?;
+
+    {
+        let mut controller = CONTROLLER.lock();
+        let config = controller.config()?;
+        let config = if enabled { config | TRANSLATION } else { config & !TRANSLATION };
+        controller.set_config(config)?;
+    }
+
+    keymap::set_scancode_set(if enabled { ScancodeSet::Set1 } else { ScancodeSet::Set2 });
+    info.translation = enabled;
+    *INFO.lock() = Some(info);
+    Ok(())
+}
+
+// Keyboard commands
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Leds {
+    pub scroll_lock: bool,
+    pub num_lock: bool,
+    pub caps_lock: bool,
+}
+impl Leds {
+    fn as_byte(&self) -> u8 {
+        (self.scroll_lock as u8) | (self.num_lock as u8) << 1 | (self.caps_lock as u8) << 2
+    }
+}
+
+// A command in flight: the bytes, how many were acknowledged, whether the next one went out and
+// when it was sent (or queued, while it didn't)
+#[derive(Clone, Copy)]
+struct Command {
+    bytes: [u8; 2],
+    acknowledged: usize,
+    resends: u8,
+    sent: bool,
+    sent_at: u64,
+}
+
+// Only the latest LED state and typematic setting matter, so instead of a real queue there is one
+// pending slot for each.
+struct CommandQueue {
+    current: Option<Command>,
+    leds: Option<u8>,
+    typematic: Option<u8>,
+}
+impl CommandQueue {
+    const fn new() -> Self {
+        CommandQueue {
+            current: None,
+            leds: None,
+            typematic: None,
+        }
+    }
+
+    fn start_next(&mut self) {
+        let bytes = if let Some(leds) = self.leds.take() {
+            [0xED, leds]
+        } else if let Some(typematic) = self.typematic.take() {
+            [0xF3, typematic]
+        } else {
+            return;
+        };
+        let command = Command {
+            bytes,
+            acknowledged: 0,
+            resends: 0,
+            sent: false,
+            sent_at: time::ticks(),
+        };
+        self.current = Some(command);
+        self.send_current();
+    }
+
+    // Doesn't wait for the controller, if it has no room `kick` tries again
+    fn send_current(&mut self) {
+        if let Some(command) = &mut self.current {
+            let byte = command.bytes[command.acknowledged];
+            command.sent = CONTROLLER.lock().try_write(byte);
+            if command.sent {
+                command.sent_at = time::ticks();
+            }
+        }
+    }
+
+    // Starts the next command if the keyboard is idle, or sends the byte that didn't go out. A
+    // command that got no answer for 100ms is dropped, otherwise a keyboard that missed a byte
+    // would block all further commands.
+    fn kick(&mut self) {
+        let stale = self.current.map_or(false, |command| {
+            time::ticks().saturating_sub(command.sent_at) > time::frequency() as u64 / 10
+        });
+        if stale {
+            self.current = None;
+        }
+        match self.current {
+            None => self.start_next(),
+            Some(command) if !command.sent => self.send_current(),
+            Some(_) => {}
+        }
+    }
+}
+
+fn queue_command(update: impl FnOnce(&mut CommandQueue)) -> Result<(), Ps2Error> {
+    if !info().map_or(false, |info| info.keyboard) {
+        return Err(Ps2Error::NotInitialized);
+    }
+    let mut queue = KEYBOARD_COMMANDS.lock();
+    update(&mut queue);
+    queue.kick();
+    Ok(())
+}
+
+static COMMAND_ACTION: IrqAction = IrqAction {
+    name: "ps2 commands",
+    handler: command_tick,
+};
+
+// Shares the timer line, keeps the keyboard commands going when no answer comes to drive them
+fn command_tick() -> IrqResult {
+    KEYBOARD_COMMANDS.lock().kick();
+    IrqResult::NotMine
+}
+
+// Can be called from the keyboard IRQ handler, the command is sent without waiting for the ACK
+pub fn set_leds(leds: Leds) -> Result<(), Ps2Error> {
+    queue_command(|queue| queue.leds = Some(leds.as_byte()))
+}
+
+// Sets the delay before a held key starts repeating (250 to 1000ms) and the repeat rate (2 to 30
+// per second). Both are rounded to the closest value the keyboard supports.
+pub fn set_typematic(delay_ms: u32, repeats_per_second: u32) -> Result<(), Ps2Error> {
+    let delay = ((delay_ms + 125) / 250).clamp(1, 4) - 1;
+
+    // the period of rate n is (8 + (n & 7)) * 2^((n >> 3) & 3) * 4.17ms, we compare in 10us units
+    let target = 100_000 / repeats_per_second.clamp(2, 30);
+    let rate = (0..32u32)
+        .min_by_key(|rate| {
+            let period = (8 + (rate & 7)) * (1 << ((rate >> 3) & 3)) * 417;
+            (period as i32 - target as i32).abs()
+        })
+        .unwrap_or(0);
+
+    queue_command(|queue| queue.typematic = Some((delay << 5 | rate) as u8))
+}
+
+// Called by the keyboard IRQ handler with every byte it reads. Returns true if the byte was the
+// answer to a command, then it isn't a scancode.
+pub(crate) fn keyboard_response(byte: u8) -> bool {
+    if byte != ACK && byte != RESEND {
+        return false;
+    }
+
+    let mut queue = KEYBOARD_COMMANDS.lock();
+    let mut command = match queue.current {
+        Some(command) => command,
+        None => return false,
+    };
+
+    if byte == ACK {
+        command.acknowledged += 1;
+        if command.acknowledged == command.bytes.len() {
+            queue.current = None;
+            queue.start_next();
+            return true;
+        }
+    } else {
+        command.resends += 1;
+        if command.resends > MAX_RESENDS {
+            queue.current = None;
+            queue.start_next();
+            return true;
+        }
+    }
+    queue.current = Some(command);
+    queue.send_current();
+    true
+}
@@ -1,11 +1,25 @@
 // Index:
-// Imports        6
-// SERIAL1 static 17
-// _print()       27
+// Imports          9
+// SERIAL1 static   31
+// _print()         40
+// emergency_port() 48
+// Input            52
 
 use uart_16550::SerialPort;
-use spin::Mutex;
 use lazy_static::lazy_static;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::port::Port;
+use crate::sync::IrqMutex;
+
+// port 0x3F8 is the standard port for  the first serial inferface
+const COM1: u16 = 0x3F8;
+// Interrupt Enable Register, bit 0 raises IRQ 4 when a byte was received
+const INTERRUPT_ENABLE: u16 = COM1 + 1;
+// Line Status Register, bit 0 is set while there is a received byte to read
+const LINE_STATUS: u16 = COM1 + 5;
+
+// Whether `print!` output is copied to the serial port
+static MIRROR_CONSOLE: AtomicBool = AtomicBool::new(false);
 
 // A simple way to send data is to use the serial port, an old interface standard which is no
 // longer found in modern computers. It is easy to program and QEMU can redirect the bytes to send
@@ -15,20 +29,53 @@ use lazy_static::lazy_static;
 // compatible with the 16550 UART.
 
 lazy_static! {
-    pub static ref SERIAL1: Mutex<SerialPort> = {
-        // port 0x3F8 is the standard port for  the first serial inferface
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
+    pub static ref SERIAL1: IrqMutex<SerialPort> = {
+        let mut serial_port = unsafe { SerialPort::new(COM1) };
         serial_port.init();
-        Mutex::new(serial_port)
+        IrqMutex::new(serial_port)
     };
 }
 
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
 
-    interrupts::without_interrupts(|| {
-        SERIAL1.lock().write_fmt(args).expect("Printing to serial failed");
-    })
+    SERIAL1.lock().write_fmt(args).expect("Printing to serial failed");
+}
+
+// COM1 without waiting for SERIAL1, for the watchdog's NMI handler whose CPU may be stuck holding
+// it. The port is already set up by then, sending only polls the line status.
+pub fn emergency_port() -> SerialPort {
+    unsafe { SerialPort::new(COM1) }
+}
+
+// Input
+
+// Enables the receive interrupt, the bytes are picked up by the IRQ 4 handler in task::serial
+pub fn enable_receive_interrupt() {
+    // SERIAL1's initialization resets the interrupt enable register, so it has to happen first
+    lazy_static::initialize(&SERIAL1);
+    let _serial = SERIAL1.lock();
+    unsafe { Port::<u8>::new(INTERRUPT_ENABLE).write(0x01) };
+}
+
+// Reads a received byte without waiting. uart_16550 only has a blocking receive.
+pub fn try_read() -> Option<u8> {
+    unsafe {
+        if Port::<u8>::new(LINE_STATUS).read() & 1 != 0 {
+            Some(Port::<u8>::new(COM1).read())
+        } else {
+            None
+        }
+    }
+}
+
+// When enabled, everything printed to the VGA buffer is also sent over serial, so the shell can be
+// used headless with `-serial stdio`.
+pub fn set_mirror_console(enabled: bool) {
+    MIRROR_CONSOLE.store(enabled, Ordering::Relaxed);
+}
+
+pub fn mirrors_console() -> bool {
+    MIRROR_CONSOLE.load(Ordering::Relaxed)
 }
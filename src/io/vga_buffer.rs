@@ -1,18 +1,19 @@
 // Index:
-// Imports       6
-// WRITER static 14
-// _print()      26
+// Imports            7
+// WRITER static      15
+// _print()           27
+// emergency_writer() 39
 
 use lazy_static::lazy_static;
-use spin::Mutex;
 use core::fmt;
+use crate::sync::IrqMutex;
 
 pub mod color;
 pub mod writer;
 
 // constants are initialized at compile time, this allows us to initialize constants at runtime
 lazy_static! {
-    pub static ref WRITER: Mutex<writer::Writer> = Mutex::new(writer::Writer {
+    pub static ref WRITER: IrqMutex<writer::Writer> = IrqMutex::new(writer::Writer {
         column_position: 0,
         color_code: color::ColorCode::new(
             color::Color::Green, // Text color
@@ -25,9 +26,20 @@ lazy_static! {
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
 
-    interrupts::without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap(); // don't worry, vga buffer never fails
-    });
+    WRITER.lock().write_fmt(args).unwrap(); // don't worry, vga buffer never fails
+    if super::serial::mirrors_console() {
+        super::serial::_print(args);
+    }
+}
+
+// A second writer on the VGA buffer that doesn't wait for WRITER, for the watchdog's NMI handler
+// whose CPU may be stuck holding it. It starts at the beginning of the last line, its output can
+// get mixed with that of the print it interrupted.
+pub fn emergency_writer() -> writer::Writer {
+    writer::Writer {
+        column_position: 0,
+        color_code: color::ColorCode::new(color::Color::Green, color::Color::Black),
+        buffer: unsafe { &mut *(0xb8000 as *mut writer::Buffer) },
+    }
 }
@@ -12,6 +12,13 @@ pub mod memory;
 pub mod task;
 pub mod graphics;
 pub mod shell;
+pub mod acpi;
+pub mod power;
+pub mod time;
+pub mod smp;
+pub mod cpu;
+pub mod random;
+pub mod sync;
 extern crate alloc;
 
 #[cfg(test)]
@@ -24,8 +31,17 @@ use bootloader::{
 entry_point!(test_kernel_main);
 
 #[cfg(test)]
-fn test_kernel_main(_boot_info: &'static BootInfo) -> ! {
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
+    use memory::{allocator, memory::BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
     init();
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = memory::memory::init(physical_memory_offset);
+    let mut frame_allocator = BootInfoFrameAllocator::init(&boot_info.memory_map);
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap init failed");
+    memory::memory::install(mapper, frame_allocator);
+
     test_main();
     hlt_loop();
 }
@@ -44,9 +60,17 @@ fn allow_error_handler(layout: alloc::alloc::Layout) -> ! {
 pub fn init() {
     memory::gdt::init();
     memory::interrupts::init_idt();
+    memory::syscall::init();
+    memory::fpu::init();
     unsafe {
         memory::interrupts::PICS.lock().initialize()
     };
+    memory::irq::init();
+    time::init();
+    task::keyboard::init();
+    // no mouse is fine
+    let _ = task::mouse::init();
+    task::serial::init();
     x86_64::instructions::interrupts::enable();
 }
 
@@ -78,6 +102,9 @@ pub fn test_panic_handler(info: &PanicInfo) -> ! {
     loop {}
 }
 
+// Only meant for the test harness, the isa-debug-exit device only exists with the `test-args` in
+// Cargo.toml. Use `power::shutdown` everywhere else.
+//
 // QEMU maps to different exit codes with this expression => (value << 1) | 1
 // Therefore,
 // 0 => 1
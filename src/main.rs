@@ -11,13 +11,13 @@ use bootloader::{
     entry_point
 };
 #[allow(unused_imports)]
-use cometos::{task::keyboard::ScancodeStream, print, println, graphics};
+use cometos::{task::{executor::Executor, Priority, Task}, print, println, graphics, shell};
 
 // Init and setup
 entry_point!(kernel_main);
 fn kernel_main(boot_info: &'static BootInfo) -> ! {
     // INIT
-    use cometos::memory::memory::{BootInfoFrameAllocator, init};
+    use cometos::memory::memory::{BootInfoFrameAllocator, init, install};
     use x86_64::VirtAddr;
     use cometos::memory::allocator;
 
@@ -29,14 +29,33 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap init failed");
 
-    let _scancodestream = ScancodeStream::new();
+    if let Err(error) = cometos::acpi::init().and_then(|_| cometos::acpi::fadt::init()) {
+        println!("WARNING: ACPI unavailable ({:?}), power management falls back to legacy methods", error);
+    }
+
+    cometos::time::system_time::init();
+
+    // the HPET driver maps its registers through the kernel mapper
+    install(mapper, frame_allocator);
+    if let Err(error) = cometos::time::init_hpet() {
+        println!("WARNING: HPET unavailable ({:?}), the PIT keeps driving the timer", error);
+    }
+    if let Err(error) = cometos::smp::init() {
+        println!("WARNING: application processors not started ({:?}), running on one CPU", error);
+    }
+    if let Err(error) = cometos::smp::watchdog::init(cometos::smp::watchdog::DEFAULT_THRESHOLD) {
+        println!("WARNING: lockup detector not running ({:?})", error);
+    }
 
     #[cfg(test)]
     test_main();
 
     main();
 
-    cometos::hlt_loop();
+    let mut executor = Executor::new();
+    // the shell consumes the key presses, typing shouldn't wait behind other tasks
+    executor.spawn(Task::new(shell::run_shell()).with_priority(Priority::Input));
+    executor.run();
 }
 
 fn main() {
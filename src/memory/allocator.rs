@@ -1,10 +1,10 @@
 // Index:
 // Imports          9
-// ALLOCATOR static 28
-// init_heap()      35
-// Dummy            57
-// Locked           69
-// align_up()       86
+// ALLOCATOR static 29
+// init_heap()      36
+// Dummy            58
+// Locked           71
+// align_up()       89
 
 use core::{alloc::{GlobalAlloc, Layout}, ptr::null_mut};
 use linked_list::LinkedListAllocator;
@@ -19,6 +19,7 @@ use x86_64::{
     },
     VirtAddr,
 };
+use crate::sync::{IrqMutex, IrqMutexGuard};
 
 pub mod bump;
 pub mod linked_list;
@@ -64,19 +65,21 @@ unsafe impl GlobalAlloc for Dummy {
         panic!("dealloc should be never called")
     }
 }
-// a wrapper around spin::Mutex to permit trait implentaions on:
-// "unsafe impl GlobalAlloc for spin::Mutex<BumpAllocator>"
+// a wrapper around IrqMutex to permit trait implentaions on:
+// "unsafe impl GlobalAlloc for IrqMutex<BumpAllocator>"
+// IrqMutex also keeps interrupts from arriving while the heap is locked.
 pub struct Locked<A> {
-    inner: spin::Mutex<A>,
+    inner: IrqMutex<A>,
 }
 impl<A> Locked<A> {
     pub const fn new(inner: A) -> Self {
         Locked {
-            inner: spin::Mutex::new(inner),
+            inner: IrqMutex::new(inner),
         }
     }
 
-    pub fn lock(&self) -> spin::MutexGuard<A> {
+    #[track_caller]
+    pub fn lock(&self) -> IrqMutexGuard<'_, A> {
         self.inner.lock()
     }
 }
@@ -34,7 +34,7 @@ impl BumpAllocator {
 // We have to use the mutex because in alloc function, the first argument is &self and not &mut
 // self, so we can't edit the data...
 // There is a way to get a &mut self reference from a &self reference: We can yse synchronized
-// interior mutability by wrapping the allocator in spin::Mutex spinlock. This type provides a lock
+// interior mutability by wrapping the allocator in an IrqMutex spinlock. This type provides a lock
 // method that performs mutual exclusion and this safely turn &self reference to a &mut self
 // reference.
 unsafe impl GlobalAlloc for Locked<BumpAllocator> {
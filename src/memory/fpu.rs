@@ -0,0 +1,239 @@
+// FPU, SSE and AVX state
+//
+// The kernel is built without SSE (`-mmx,-sse,+soft-float` in the target JSON), so the compiler
+// never touches the FPU or the XMM registers on its own and interrupt handlers don't have to save
+// them. `init` still enables them for the code that opts in:
+//
+// Register  Bit  Name        Meaning
+// CR0       1    MP          WAIT/FWAIT also raise #NM while TS is set
+// CR0       2    EM          emulate the FPU, cleared so FPU and SSE instructions run
+// CR0       3    TS          task switched, the next FPU or SSE instruction raises #NM
+// CR4       9    OSFXSR      the OS saves the state with FXSAVE, enables SSE
+// CR4       10   OSXMMEXCPT  unmasked SIMD exceptions raise #XM instead of #UD
+// CR4       18   OSXSAVE     enables XSAVE and XSETBV
+// XCR0      0-2  -           state XSAVE manages: x87, SSE and, if the CPU has it, AVX
+//
+// The registers belong to an FpuContext: the kernel has one, and `run_user` gives every user
+// program its own. Switching contexts only sets TS. The first FPU or SSE instruction afterwards
+// raises #NM (device not available), whose handler saves the registers into the context that
+// owns them, loads the one that is running now and clears TS. Code that never touches the FPU
+// never pays for saving it. The state is saved with XSAVE if the CPU has it and FXSAVE otherwise.
+//
+// Kernel code opts in with #[target_feature(enable = "sse2")] (or "avx", see `avx_enabled`) and
+// the core::arch intrinsics, and runs inside `with_simd`. That switches to the kernel's context,
+// so a syscall doesn't clobber the registers of the user program that made it. Plain f32 and f64
+// math stays soft-float. Interrupt handlers, the NMI handler included, must stay FPU-free and never
+// switch contexts: #NM panics if one of them isn't.
+//
+// Only the BSP switches contexts, the APs get SSE enabled with the registers to themselves.
+
+use alloc::boxed::Box;
+use core::{
+    arch::{asm, x86_64::__cpuid_count},
+    ptr::{self, addr_of, addr_of_mut},
+    sync::atomic::{AtomicBool, AtomicPtr, Ordering},
+};
+use x86_64::{
+    registers::{
+        control::{Cr0, Cr0Flags, Cr4, Cr4Flags},
+        xcontrol::{XCr0, XCr0Flags},
+    },
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame},
+};
+use crate::{cpu::{self, Feature}, smp::{self, watchdog}};
+use super::irq;
+
+// The legacy area (512 bytes), the XSAVE header (64 bytes) and the AVX state (256 bytes) fit
+const SAVE_AREA_SIZE: usize = 1024;
+// All exceptions masked, round to nearest
+const MXCSR_DEFAULT: u32 = 0x1F80;
+
+// XSAVE needs 64 byte alignment, FXSAVE 16
+#[repr(C, align(64))]
+struct SaveArea([u8; SAVE_AREA_SIZE]);
+
+static mut KERNEL: SaveArea = SaveArea([0; SAVE_AREA_SIZE]);
+// The state right after `init`, every new context starts with it
+static mut INITIAL: SaveArea = SaveArea([0; SAVE_AREA_SIZE]);
+
+// The context whose state is in the registers, null if nobody wants it anymore
+static OWNER: AtomicPtr<SaveArea> = AtomicPtr::new(ptr::null_mut());
+// The context the running code expects in the registers
+static CURRENT: AtomicPtr<SaveArea> = AtomicPtr::new(ptr::null_mut());
+static XSAVE: AtomicBool = AtomicBool::new(false);
+
+// Taking the address of a static mut is only safe on newer toolchains
+#[allow(unused_unsafe)]
+fn kernel_area() -> *mut SaveArea {
+    unsafe { addr_of_mut!(KERNEL) }
+}
+
+// Bytes XSAVE writes for the components enabled in XCR0
+#[allow(unused_unsafe)]
+fn xsave_size() -> usize {
+    unsafe { __cpuid_count(0xD, 0).ebx as usize }
+}
+
+unsafe fn save(area: *mut SaveArea) {
+    if XSAVE.load(Ordering::Relaxed) {
+        asm!("xsave64 [{}]", in(reg) area, in("eax") u32::MAX, in("edx") u32::MAX, options(nostack));
+    } else {
+        asm!("fxsave64 [{}]", in(reg) area, options(nostack));
+    }
+}
+
+unsafe fn restore(area: *const SaveArea) {
+    if XSAVE.load(Ordering::Relaxed) {
+        asm!("xrstor64 [{}]", in(reg) area, in("eax") u32::MAX, in("edx") u32::MAX, options(nostack));
+    } else {
+        asm!("fxrstor64 [{}]", in(reg) area, options(nostack));
+    }
+}
+
+// Enables the FPU, SSE and AVX on the CPU we are running on, every CPU has to call it once. On
+// the BSP the registers then belong to the kernel's context.
+pub fn init() {
+    let xsave = cpu::has(Feature::Xsave);
+    unsafe {
+        Cr0::update(|flags| {
+            flags.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+            flags.insert(Cr0Flags::MONITOR_COPROCESSOR);
+        });
+        Cr4::update(|flags| {
+            flags.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE);
+            flags.set(Cr4Flags::OSXSAVE, xsave);
+        });
+        if xsave {
+            let legacy = XCr0Flags::X87 | XCr0Flags::SSE;
+            XCr0::write(if cpu::has(Feature::Avx) { legacy | XCr0Flags::AVX } else { legacy });
+            if xsave_size() > SAVE_AREA_SIZE {
+                XCr0::write(legacy);
+            }
+        }
+
+        asm!("fninit", options(nomem, nostack));
+        asm!("ldmxcsr [{}]", in(reg) &MXCSR_DEFAULT, options(nostack, readonly));
+    }
+    XSAVE.store(xsave, Ordering::Relaxed);
+
+    if smp::current().is_bootstrap() {
+        unsafe { save(addr_of_mut!(INITIAL)) };
+        OWNER.store(kernel_area(), Ordering::Relaxed);
+        CURRENT.store(kernel_area(), Ordering::Relaxed);
+    }
+}
+
+// Whether code in `with_simd` may use AVX
+pub fn avx_enabled() -> bool {
+    XSAVE.load(Ordering::Relaxed) && XCr0::read().contains(XCr0Flags::AVX)
+}
+
+// FPU and SSE registers of one user program
+pub struct FpuContext {
+    area: Box<SaveArea>,
+}
+impl FpuContext {
+    // Starts out with the state right after `init`
+    pub fn new() -> FpuContext {
+        let mut area = Box::new(SaveArea([0; SAVE_AREA_SIZE]));
+        area.0.copy_from_slice(unsafe { &(*addr_of!(INITIAL)).0 });
+        FpuContext { area }
+    }
+
+    fn as_ptr(&mut self) -> *mut SaveArea {
+        &mut *self.area
+    }
+}
+impl Drop for FpuContext {
+    fn drop(&mut self) {
+        let area = self.as_ptr();
+        assert!(CURRENT.load(Ordering::Relaxed) != area, "dropping the running FPU context");
+        // whatever the registers hold doesn't need to be saved anymore
+        let _ = OWNER.compare_exchange(area, ptr::null_mut(), Ordering::Relaxed, Ordering::Relaxed);
+    }
+}
+
+// Makes `area` the running context, the registers follow on the next FPU or SSE instruction
+fn switch_to(area: *mut SaveArea) {
+    CURRENT.store(area, Ordering::Relaxed);
+    unsafe {
+        if OWNER.load(Ordering::Relaxed) == area {
+            asm!("clts", options(nomem, nostack));
+        } else {
+            Cr0::update(|flags| flags.insert(Cr0Flags::TASK_SWITCHED));
+        }
+    }
+}
+
+// Runs `f` with the registers of `context`, the previous context is running again afterwards
+pub fn with_context<R>(context: &mut FpuContext, f: impl FnOnce() -> R) -> R {
+    let previous = CURRENT.load(Ordering::Relaxed);
+    switch_to(context.as_ptr());
+    let result = f();
+    switch_to(previous);
+    result
+}
+
+// Runs `f` with the kernel's registers, for code that uses the FPU, SSE or AVX. Can't be used in
+// interrupt handlers.
+pub fn with_simd<R>(f: impl FnOnce() -> R) -> R {
+    assert!(!irq::in_handler() && !watchdog::in_nmi(), "SIMD in an interrupt handler");
+    let previous = CURRENT.load(Ordering::Relaxed);
+    switch_to(kernel_area());
+    let result = f();
+    switch_to(previous);
+    result
+}
+
+extern "x86-interrupt" fn device_not_available_handler(_stack_frame: InterruptStackFrame) {
+    if irq::in_handler() || watchdog::in_nmi() {
+        panic!("FPU or SSE instruction in an interrupt handler");
+    }
+    unsafe { asm!("clts", options(nomem, nostack)) };
+    if !smp::current().is_bootstrap() {
+        return;
+    }
+
+    let owner = OWNER.load(Ordering::Relaxed);
+    let current = CURRENT.load(Ordering::Relaxed);
+    if owner != current {
+        unsafe {
+            if !owner.is_null() {
+                save(owner);
+            }
+            restore(current);
+        }
+        OWNER.store(current, Ordering::Relaxed);
+    }
+}
+
+extern "x86-interrupt" fn simd_floating_point_handler(stack_frame: InterruptStackFrame) {
+    panic!("EXCEPTION: SIMD FLOATING POINT\n{:#?}", stack_frame);
+}
+
+pub(crate) fn set_idt_entries(idt: &mut InterruptDescriptorTable) {
+    idt.device_not_available.set_handler_fn(device_not_available_handler);
+    idt.simd_floating_point.set_handler_fn(simd_floating_point_handler);
+}
+
+// Tests
+#[test_case]
+fn test_lazy_switch() {
+    fn mxcsr() -> u32 {
+        let mut value = 0u32;
+        unsafe { asm!("stmxcsr [{}]", in(reg) &mut value, options(nostack)) };
+        value
+    }
+    fn set_mxcsr(value: u32) {
+        unsafe { asm!("ldmxcsr [{}]", in(reg) &value, options(nostack, readonly)) };
+    }
+
+    with_simd(|| {
+        // round toward zero
+        set_mxcsr(MXCSR_DEFAULT | 0x6000);
+        let mut context = FpuContext::new();
+        assert_eq!(with_context(&mut context, mxcsr), MXCSR_DEFAULT);
+        assert_eq!(mxcsr(), MXCSR_DEFAULT | 0x6000);
+        set_mxcsr(MXCSR_DEFAULT);
+    });
+}
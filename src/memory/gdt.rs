@@ -1,8 +1,9 @@
 // Index:
-// Imports    12
-// TSS static 21
-// Selectors  37
-// init()     50
+// Imports    13
+// TSS static 27
+// Selectors  57
+// init()     94
+// init_ap()  99
 //
 //
 // GDT is a relic that was used for memory segmentation before paging became the de facto standard.
@@ -15,8 +16,13 @@ use x86_64::structures::{
     gdt::{GlobalDescriptorTable, Descriptor, SegmentSelector}
 };
 use lazy_static::lazy_static;
+use alloc::boxed::Box;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+// An NMI can arrive anywhere, even right after SYSCALL while RSP is still the user's
+pub const NMI_IST_INDEX: u16 = 1;
+// Privilege stack table entry the CPU switches to on interrupts from ring 3
+pub const KERNEL_STACK_INDEX: usize = 0;
 
 lazy_static! {
     static ref TSS: TaskStateSegment = {
@@ -29,33 +35,89 @@ lazy_static! {
             let stack_end = stack_start + STACK_SIZE;
             stack_end
         };
+        tss.interrupt_stack_table[NMI_IST_INDEX as usize] = {
+            const STACK_SIZE: usize = 4096 * 5;
+            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+            stack_start + STACK_SIZE
+        };
+        tss.privilege_stack_table[KERNEL_STACK_INDEX] = {
+            const STACK_SIZE: usize = 4096 * 5;
+            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+            stack_start + STACK_SIZE
+        };
         tss
     };
 }
 
 
-struct Selectors {
-    code_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
+// The order of the segments is fixed by SYSCALL/SYSRET, see memory::syscall. SYSCALL loads the
+// kernel data selector from kernel code + 8, SYSRET loads user data from kernel data + 8 and user
+// code from kernel data + 16.
+//
+// Selector  Segment
+// 0x08      kernel code
+// 0x10      kernel data
+// 0x1B      user data (RPL 3)
+// 0x23      user code (RPL 3)
+// 0x28      TSS
+#[derive(Debug, Clone, Copy)]
+pub struct Selectors {
+    pub kernel_code: SegmentSelector,
+    pub kernel_data: SegmentSelector,
+    pub user_data: SegmentSelector,
+    pub user_code: SegmentSelector,
+    pub tss: SegmentSelector,
 }
 lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (gdt, Selectors { code_selector, tss_selector })
-    };
+    static ref GDT: (GlobalDescriptorTable, Selectors) = build(&TSS);
+}
+
+// Every CPU gets the same layout, only the TSS differs
+fn build(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    let kernel_code = gdt.add_entry(Descriptor::kernel_code_segment());
+    let kernel_data = gdt.add_entry(Descriptor::kernel_data_segment());
+    let user_data = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code = gdt.add_entry(Descriptor::user_code_segment());
+    let tss = gdt.add_entry(Descriptor::tss_segment(tss));
+    (gdt, Selectors { kernel_code, kernel_data, user_data, user_code, tss })
+}
+
+pub fn selectors() -> Selectors {
+    GDT.1
 }
 
 pub fn init() {
+    load(&GDT);
+}
+
+// GDT and TSS of an application processor, with stacks mapped by the caller. Needs the heap.
+pub fn init_ap(double_fault_stack: VirtAddr, nmi_stack: VirtAddr, kernel_stack: VirtAddr) {
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = double_fault_stack;
+    tss.interrupt_stack_table[NMI_IST_INDEX as usize] = nmi_stack;
+    tss.privilege_stack_table[KERNEL_STACK_INDEX] = kernel_stack;
+    let tss = Box::leak(Box::new(tss));
+    load(Box::leak(Box::new(build(tss))));
+}
+
+fn load(gdt: &'static (GlobalDescriptorTable, Selectors)) {
     use x86_64::instructions::{
         tables::load_tss,
-        segmentation::{CS, Segment},
+        segmentation::{CS, DS, ES, SS, Segment},
     };
 
-    GDT.0.load();
+    gdt.0.load();
     unsafe {
-        CS::set_reg(GDT.1.code_selector);
-        load_tss(GDT.1.tss_selector);
+        CS::set_reg(gdt.1.kernel_code);
+        // the bootloader's selectors don't mean anything in our GDT
+        SS::set_reg(gdt.1.kernel_data);
+        DS::set_reg(gdt.1.kernel_data);
+        ES::set_reg(gdt.1.kernel_data);
+        load_tss(gdt.1.tss);
     }
 }
@@ -1,10 +1,10 @@
 // Index:
 // Imports                  84
-// IDT static               91
-// init_idt()               108
-// Hardware Interrupt Setup 112
-// Exception Handlers       134
-// Tests                    184
+// IDT static               89
+// init_idt()               107
+// Hardware Interrupt Setup 111
+// Exception Handlers       118
+// Tests                    135
 //
 // InterruptDescriptorTable (IDT)
 // IDT is used to catch and handle exception
@@ -84,9 +84,7 @@
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
-use spin;
-use crate::{println, hlt_loop, memory::gdt};
-// use super::super::shell::get_char;
+use crate::{println, hlt_loop, memory::{fpu, gdt, irq, syscall}, smp::{apic, watchdog}, sync::IrqMutex};
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
@@ -97,10 +95,11 @@ lazy_static! {
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
             idt.page_fault.set_handler_fn(page_fault_handler);
         }
-        idt[InterruptIndex::Timer.as_usize()]
-            .set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()]
-            .set_handler_fn(keyboard_interrupt_handler);
+        irq::set_idt_entries(&mut idt);
+        fpu::set_idt_entries(&mut idt);
+        apic::set_idt_entry(&mut idt);
+        watchdog::set_idt_entries(&mut idt);
+        syscall::set_idt_entry(&mut idt);
         idt
     };
 }
@@ -110,26 +109,11 @@ pub fn init_idt() {
 }
 
 // Hardware Interrupt setup
+// The handlers for the PIC lines are registered at runtime, see memory::irq
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
-pub static PICS: spin::Mutex<ChainedPics> = spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
-
-#[derive(Debug, Clone, Copy)]
-#[repr(u8)]
-pub enum InterruptIndex {
-    Timer = PIC_1_OFFSET,
-    Keyboard,
-}
-impl InterruptIndex {
-    fn as_u8(self) -> u8 {
-        self as u8
-    }
-
-    fn as_usize(self) -> usize {
-        usize::from(self.as_u8())
-    }
-}
+pub static PICS: IrqMutex<ChainedPics> = IrqMutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
 // Exception Handler
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
@@ -138,41 +122,6 @@ extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
 extern "x86-interrupt" fn double_fault_handler(stack_frame: InterruptStackFrame, _error_code: u64) -> ! {
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-    }
-}
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    use pc_keyboard::{layouts, HandleControl, Keyboard, ScancodeSet1};
-    use spin::Mutex;
-    use x86_64::instructions::port::Port;
-
-    lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-            Mutex::new(Keyboard::new(layouts::Us104Key, ScancodeSet1,
-                HandleControl::Ignore)
-            );
-    }
-
-    let mut keyboard = KEYBOARD.lock();
-    let mut port = Port::new(0x60);
-
-    let scancode: u8 = unsafe { port.read() };
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            
-            // Uncomment to toggle shell
-            // get_char(key);
-        }
-    }
-    crate::task::keyboard::add_scancode(scancode);
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
-}
 extern "x86-interrupt" fn page_fault_handler(stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode) {
     use x86_64::registers::control::Cr2;
 
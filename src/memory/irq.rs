@@ -0,0 +1,315 @@
+// Runtime registration of hardware interrupt (IRQ) handlers
+//
+// The IDT gets a stub for each of the 16 PIC lines, the stubs look up the handlers registered for
+// their line and call them one after another. Lines can be shared: every handler reports whether
+// its device raised the interrupt, and an interrupt no handler claims is counted as unhandled.
+// The EOI is sent by the stub, so handlers don't have to care about the PIC at all.
+//
+// Line  Device
+// 0     PIT (or HPET comparator 0)
+// 1     PS/2 keyboard
+// 2     cascade, the secondary PIC is chained here
+// 4     COM1
+// 8     CMOS RTC (or HPET comparator 1)
+// 12    PS/2 mouse
+//
+// Handlers are stored as pointers in fixed slots, so the interrupt stub finds them without a lock
+// or an allocation. A line is unmasked when its first handler is registered and masked again when
+// the last one is removed. Registering and unregistering are serialized by a lock, so a duplicate
+// can't slip in and a line isn't masked while another handler is being added to it.
+//
+// Handlers, and everything they call, run with interrupts disabled and may interrupt any code
+// holding any lock: they must not block or allocate.
+//
+// Spurious interrupts
+//
+// When an IRQ goes away before the PIC delivers it to the CPU, the PIC still raises an interrupt,
+// but reports its lowest priority line: IRQ 7 on the primary PIC and IRQ 15 on the secondary one.
+// Unlike a real IRQ, the corresponding bit in the in-service register (ISR) isn't set, so the PIC
+// doesn't expect an EOI. For a spurious IRQ 15 the primary PIC did see a real IRQ 2 from the
+// secondary one though, so only the primary PIC gets an EOI.
+
+use core::{
+    ptr,
+    sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering},
+};
+use x86_64::{
+    instructions::port::Port,
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame},
+};
+use crate::sync::IrqMutex;
+use super::interrupts::{PICS, PIC_1_OFFSET};
+
+pub const IRQ_LINES: usize = 16;
+// Maximum number of handlers sharing one line
+pub const HANDLERS_PER_LINE: usize = 4;
+
+pub const TIMER: u8 = 0;
+pub const KEYBOARD: u8 = 1;
+pub const CASCADE: u8 = 2;
+pub const COM1: u8 = 4;
+pub const RTC: u8 = 8;
+pub const MOUSE: u8 = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqResult {
+    Handled,
+    // The device of this handler didn't raise the interrupt, try the next one
+    NotMine,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    InvalidLine,
+    LineFull,
+    AlreadyRegistered,
+    NotRegistered,
+}
+
+// A handler together with a name for `irqstat`
+pub struct IrqAction {
+    pub name: &'static str,
+    pub handler: fn() -> IrqResult,
+}
+
+static HANDLERS: [[AtomicPtr<IrqAction>; HANDLERS_PER_LINE]; IRQ_LINES] =
+    [const { [const { AtomicPtr::new(ptr::null_mut()) }; HANDLERS_PER_LINE] }; IRQ_LINES];
+
+static COUNTS: [AtomicU64; IRQ_LINES] = [const { AtomicU64::new(0) }; IRQ_LINES];
+static UNHANDLED: [AtomicU64; IRQ_LINES] = [const { AtomicU64::new(0) }; IRQ_LINES];
+static SPURIOUS: [AtomicU64; IRQ_LINES] = [const { AtomicU64::new(0) }; IRQ_LINES];
+// Number of `dispatch` calls currently running
+static DEPTH: AtomicUsize = AtomicUsize::new(0);
+// Held by `register` and `unregister`, the interrupt stubs don't need it
+static REGISTRATION: IrqMutex<()> = IrqMutex::new(());
+
+// OCW3 command that makes the next read of the command port return the ISR
+const READ_ISR: u8 = 0x0B;
+const PRIMARY_COMMAND: u16 = 0x20;
+const SECONDARY_COMMAND: u16 = 0xA0;
+
+// Masks every line, drivers unmask theirs by registering a handler. Must be called after the PICs
+// were initialized.
+pub fn init() {
+    unsafe { PICS.lock().write_masks(0xFF, 0xFF) };
+}
+
+pub fn register(line: u8, action: &'static IrqAction) -> Result<(), IrqError> {
+    let slots = HANDLERS.get(line as usize).ok_or(IrqError::InvalidLine)?;
+    let action = action as *const IrqAction as *mut IrqAction;
+    let _registration = REGISTRATION.lock();
+
+    if slots.iter().any(|slot| slot.load(Ordering::Acquire) == action) {
+        return Err(IrqError::AlreadyRegistered);
+    }
+    let registered = slots.iter().any(|slot| {
+        slot.compare_exchange(ptr::null_mut(), action, Ordering::AcqRel, Ordering::Acquire).is_ok()
+    });
+    if !registered {
+        return Err(IrqError::LineFull);
+    }
+
+    unmask(line);
+    Ok(())
+}
+
+pub fn unregister(line: u8, action: &'static IrqAction) -> Result<(), IrqError> {
+    let slots = HANDLERS.get(line as usize).ok_or(IrqError::InvalidLine)?;
+    let action = action as *const IrqAction as *mut IrqAction;
+    let _registration = REGISTRATION.lock();
+
+    let removed = slots.iter().any(|slot| {
+        slot.compare_exchange(action, ptr::null_mut(), Ordering::AcqRel, Ordering::Acquire).is_ok()
+    });
+    if !removed {
+        return Err(IrqError::NotRegistered);
+    }
+
+    if handlers(line).next().is_none() {
+        mask(line);
+    }
+    Ok(())
+}
+
+// Handlers registered for `line`, in the order they are called
+pub fn handlers(line: u8) -> impl Iterator<Item = &'static IrqAction> {
+    HANDLERS[line as usize].iter().filter_map(|slot| {
+        // registered actions are &'static, so the pointer stays valid
+        unsafe { slot.load(Ordering::Acquire).as_ref() }
+    })
+}
+
+pub fn mask(line: u8) {
+    if line as usize >= IRQ_LINES {
+        return;
+    }
+    let mut pics = PICS.lock();
+    unsafe {
+        let [primary, secondary] = pics.read_masks();
+        if line < 8 {
+            pics.write_masks(primary | (1 << line), secondary);
+        } else {
+            pics.write_masks(primary, secondary | (1 << (line - 8)));
+        }
+    }
+}
+
+// Lines of the secondary PIC also need the cascade line of the primary PIC
+pub fn unmask(line: u8) {
+    if line as usize >= IRQ_LINES {
+        return;
+    }
+    let mut pics = PICS.lock();
+    unsafe {
+        let [primary, secondary] = pics.read_masks();
+        if line < 8 {
+            pics.write_masks(primary & !(1 << line), secondary);
+        } else {
+            pics.write_masks(primary & !(1 << CASCADE), secondary & !(1 << (line - 8)));
+        }
+    }
+}
+
+pub fn is_masked(line: u8) -> bool {
+    let [primary, secondary] = unsafe { PICS.lock().read_masks() };
+    if line < 8 {
+        primary & (1 << line) != 0
+    } else {
+        secondary & (1 << (line - 8)) != 0
+    }
+}
+
+// Number of interrupts raised on `line` since boot
+pub fn count(line: u8) -> u64 {
+    COUNTS[line as usize].load(Ordering::Relaxed)
+}
+
+// Number of interrupts on `line` no handler claimed
+pub fn unhandled(line: u8) -> u64 {
+    UNHANDLED[line as usize].load(Ordering::Relaxed)
+}
+
+// Number of spurious interrupts reported on `line`, only ever non-zero for IRQ 7 and 15
+pub fn spurious(line: u8) -> u64 {
+    SPURIOUS[line as usize].load(Ordering::Relaxed)
+}
+
+// Whether we are inside an IRQ handler
+pub fn in_handler() -> bool {
+    DEPTH.load(Ordering::Relaxed) != 0
+}
+
+// In-service register of the primary (low byte) and secondary (high byte) PIC
+fn read_isr() -> u16 {
+    let mut primary = Port::<u8>::new(PRIMARY_COMMAND);
+    let mut secondary = Port::<u8>::new(SECONDARY_COMMAND);
+    unsafe {
+        primary.write(READ_ISR);
+        secondary.write(READ_ISR);
+        (secondary.read() as u16) << 8 | primary.read() as u16
+    }
+}
+
+// Checks whether an interrupt on IRQ 7 or 15 is spurious, and if so sends the EOI it needs
+fn is_spurious(line: u8) -> bool {
+    if line != 7 && line != 15 {
+        return false;
+    }
+    // keep the PICS lock while touching the command ports, EOIs go through them too
+    let mut pics = PICS.lock();
+    if read_isr() & (1 << line) != 0 {
+        return false;
+    }
+
+    SPURIOUS[line as usize].fetch_add(1, Ordering::Relaxed);
+    if line == 15 {
+        // any interrupt number of the primary PIC works, it only gets the EOI for the cascade
+        unsafe { pics.notify_end_of_interrupt(PIC_1_OFFSET) };
+    }
+    true
+}
+
+fn dispatch(line: u8) {
+    if is_spurious(line) {
+        return;
+    }
+    COUNTS[line as usize].fetch_add(1, Ordering::Relaxed);
+    crate::random::add_interrupt_entropy(line);
+    DEPTH.fetch_add(1, Ordering::Relaxed);
+
+    // every handler runs, more than one device on a shared line might need service
+    let mut handled = false;
+    for action in handlers(line) {
+        if (action.handler)() == IrqResult::Handled {
+            handled = true;
+        }
+    }
+    if !handled {
+        UNHANDLED[line as usize].fetch_add(1, Ordering::Relaxed);
+    }
+    DEPTH.fetch_sub(1, Ordering::Relaxed);
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(PIC_1_OFFSET + line);
+    }
+}
+
+// One stub per line, the x86-interrupt ABI doesn't tell the handler which vector it was called for
+macro_rules! irq_stubs {
+    ($($line:literal => $name:ident),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $name(_stack_frame: InterruptStackFrame) {
+                dispatch($line);
+            }
+        )*
+
+        const STUBS: [extern "x86-interrupt" fn(InterruptStackFrame); IRQ_LINES] = [$($name),*];
+    };
+}
+irq_stubs! {
+    0 => irq0, 1 => irq1, 2 => irq2, 3 => irq3, 4 => irq4, 5 => irq5, 6 => irq6, 7 => irq7,
+    8 => irq8, 9 => irq9, 10 => irq10, 11 => irq11, 12 => irq12, 13 => irq13, 14 => irq14, 15 => irq15,
+}
+
+// Points the 16 vectors after PIC_1_OFFSET to the stubs
+pub(crate) fn set_idt_entries(idt: &mut InterruptDescriptorTable) {
+    for (line, stub) in STUBS.iter().enumerate() {
+        idt[PIC_1_OFFSET as usize + line].set_handler_fn(*stub);
+    }
+}
+
+// Tests
+#[test_case]
+fn test_shared_line() {
+    use core::sync::atomic::AtomicBool;
+
+    static FIRST_CALLED: AtomicBool = AtomicBool::new(false);
+    static SECOND_CALLED: AtomicBool = AtomicBool::new(false);
+    static FIRST: IrqAction = IrqAction {
+        name: "test-first",
+        handler: || {
+            FIRST_CALLED.store(true, Ordering::SeqCst);
+            IrqResult::NotMine
+        },
+    };
+    static SECOND: IrqAction = IrqAction {
+        name: "test-second",
+        handler: || {
+            SECOND_CALLED.store(true, Ordering::SeqCst);
+            IrqResult::Handled
+        },
+    };
+
+    // IRQ 0 is the timer, both handlers run on the next tick next to the time keeping one
+    register(TIMER, &FIRST).unwrap();
+    register(TIMER, &SECOND).unwrap();
+    assert_eq!(register(TIMER, &FIRST), Err(IrqError::AlreadyRegistered));
+    while !(FIRST_CALLED.load(Ordering::SeqCst) && SECOND_CALLED.load(Ordering::SeqCst)) {
+        x86_64::instructions::hlt();
+    }
+    unregister(TIMER, &FIRST).unwrap();
+    unregister(TIMER, &SECOND).unwrap();
+    assert_eq!(unregister(TIMER, &SECOND), Err(IrqError::NotRegistered));
+    assert!(!is_masked(TIMER));
+}
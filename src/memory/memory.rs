@@ -1,10 +1,17 @@
 // Index:
-// Imports                  46
-// init()                   59
-// active_level4_table()    63
-// create_example_mapping() 75
-// EmptyFrameAllocator      88
-// BootInfoFrameAllocator   97
+// Imports                  53
+// PHYSICAL_MEMORY_OFFSET   67
+// init()                   75
+// active_level4_table()    80
+// phys_to_virt()           95
+// create_example_mapping() 101
+// EmptyFrameAllocator      114
+// BootInfoFrameAllocator   124
+// KERNEL_MEMORY            163
+// map_mmio()               179
+// map_user()               201
+// is_user_accessible()     221
+// allocate_stack()         253
 //
 //
 // Page Table format
@@ -46,9 +53,18 @@
 use x86_64::{
     PhysAddr,
     VirtAddr,
-    structures::paging::{Page, PhysFrame, Mapper, Size4KiB, FrameAllocator, PageTable, OffsetPageTable},
+    structures::paging::{
+        mapper::{MapToError, TranslateError},
+        Page, PhysFrame, Mapper, Size4KiB, FrameAllocator, PageTable, OffsetPageTable, PageTableFlags,
+    },
 };
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::sync::IrqMutex;
+
+// The bootloader maps the complete physical memory at this offset. We keep a copy around so that
+// drivers (ACPI tables, MMIO registers, ...) can turn physical addresses into virtual ones.
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
 
 // Returns a mutable reference to the active level 4 table.
 //
@@ -57,6 +73,7 @@ use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
 // `physical_memory_offset`. Also, this function mst be only called once
 // to avoid aliasing `&mut` references (which is undefined behavior).
 pub fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
     let level4_table = active_level4_table(physical_memory_offset);
     unsafe { OffsetPageTable::new(level4_table, physical_memory_offset) }
 }
@@ -72,6 +89,15 @@ fn active_level4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTab
     unsafe { &mut *page_table_ptr }
 }
 
+// Returns the virtual address through which the given physical address can be accessed.
+//
+// Only valid after `init` was called, before that there is no physical memory offset.
+pub fn phys_to_virt(physical_address: PhysAddr) -> VirtAddr {
+    let offset = PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed);
+    assert!(offset != 0, "physical memory offset not initialized");
+    VirtAddr::new(offset + physical_address.as_u64())
+}
+
 pub fn create_example_mapping(page: Page, mapper: &mut OffsetPageTable, frame_allocator: &mut impl FrameAllocator<Size4KiB>) {
     use x86_64::structures::paging::PageTableFlags as Flags;
 
@@ -93,6 +119,8 @@ unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
     }
 }
 
+const LOW_MEMORY_END: u64 = 0x10_0000;
+
 // A FrameAllocator that returns usable frames from the bootloader's memory map
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
@@ -115,6 +143,8 @@ impl BootInfoFrameAllocator {
         let address_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
         // transform to an itterator of frame from start addresses
         let frame_addresses = address_ranges.flat_map(|r| r.step_by(4096));
+        // the first MiB is left alone, the SMP trampoline and the firmware tables live there
+        let frame_addresses = frame_addresses.filter(|address| *address >= LOW_MEMORY_END);
         // create `PhysFrame` types from the start addresses
         frame_addresses.map(|address| PhysFrame::containing_address(PhysAddr::new(address)))
     }
@@ -127,3 +157,110 @@ unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
         frame
     }
 }
+
+// Once the heap is set up, the mapper and the frame allocator are handed over to this static, so
+// that drivers can map MMIO registers at runtime.
+static KERNEL_MEMORY: IrqMutex<Option<(OffsetPageTable<'static>, BootInfoFrameAllocator)>> = IrqMutex::new(None);
+
+pub fn install(mapper: OffsetPageTable<'static>, frame_allocator: BootInfoFrameAllocator) {
+    *KERNEL_MEMORY.lock() = Some((mapper, frame_allocator));
+}
+
+// Runs `f` with the kernel's mapper and frame allocator, returns None if `install` wasn't called
+pub fn with_mapper<R>(f: impl FnOnce(&mut OffsetPageTable<'static>, &mut BootInfoFrameAllocator) -> R) -> Option<R> {
+    let mut memory = KERNEL_MEMORY.lock();
+    memory.as_mut().map(|(mapper, frame_allocator)| f(mapper, frame_allocator))
+}
+
+// Makes sure the physical region `start..start + size` can be accessed through `phys_to_virt`
+// and returns the virtual address of `start`. The bootloader only maps the physical memory that
+// appears in the memory map, device registers (HPET, APIC, ...) usually don't, so we map the
+// missing pages ourselves with caching disabled.
+pub fn map_mmio(start: PhysAddr, size: u64) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    let first_frame = PhysFrame::<Size4KiB>::containing_address(start);
+    let last_frame = PhysFrame::<Size4KiB>::containing_address(start + size.max(1) - 1u64);
+
+    with_mapper(|mapper, frame_allocator| {
+        for frame in PhysFrame::range_inclusive(first_frame, last_frame) {
+            let page = Page::containing_address(phys_to_virt(frame.start_address()));
+            match mapper.translate_page(page) {
+                Err(TranslateError::PageNotMapped) => {
+                    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+                    unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+                }
+                // already mapped, either by the bootloader or by an earlier call
+                _ => {}
+            }
+        }
+        Ok(phys_to_virt(start))
+    }).unwrap_or(Err(MapToError::FrameAllocationFailed))
+}
+
+// Maps fresh, zeroed frames to `start..start + size` with `flags`, for code and data that runs in
+// ring 3. The pages get USER_ACCESSIBLE, and so do the page tables above them.
+pub fn map_user(start: VirtAddr, size: u64, flags: PageTableFlags) -> Result<(), MapToError<Size4KiB>> {
+    let first_page = Page::<Size4KiB>::containing_address(start);
+    let last_page = Page::<Size4KiB>::containing_address(start + size.max(1) - 1u64);
+    let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+
+    with_mapper(|mapper, frame_allocator| {
+        for page in Page::range_inclusive(first_page, last_page) {
+            let frame = frame_allocator.allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
+            unsafe {
+                phys_to_virt(frame.start_address()).as_mut_ptr::<u8>().write_bytes(0, 4096);
+                mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+            }
+        }
+        Ok(())
+    }).unwrap_or(Err(MapToError::FrameAllocationFailed))
+}
+
+// Whether ring 3 may read every page of `start..start + size`: each one needs PRESENT and
+// USER_ACCESSIBLE in the entries of all levels above it too, the CPU checks them all. `translate`
+// only reports the flags of the last level, so this walks the tables itself.
+pub fn is_user_accessible(start: VirtAddr, size: u64) -> bool {
+    let first_page = Page::<Size4KiB>::containing_address(start);
+    let last_page = Page::<Size4KiB>::containing_address(start + size.max(1) - 1u64);
+    let required = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+
+    with_mapper(|mapper, _| {
+        let level4_table: &PageTable = mapper.level_4_table();
+        Page::range_inclusive(first_page, last_page).all(|page| {
+            let indexes = [page.p4_index(), page.p3_index(), page.p2_index(), page.p1_index()];
+            let mut table = level4_table;
+            for (level, index) in indexes.iter().enumerate() {
+                let entry = &table[*index];
+                if !entry.flags().contains(required) {
+                    return false;
+                }
+                // the level 1 entry, or a huge page in level 3 or 2, maps the page itself
+                if level == 3 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                    return true;
+                }
+                table = unsafe { &*phys_to_virt(entry.addr()).as_ptr() };
+            }
+            false
+        })
+    }).unwrap_or(false)
+}
+
+// Kernel stacks that aren't known at compile time (e.g. per CPU) are mapped here, each one below an
+// unmapped guard page, so an overflow page faults instead of silently corrupting the next stack.
+const STACKS_START: u64 = 0x5555_0000_0000;
+static NEXT_STACK: AtomicU64 = AtomicU64::new(STACKS_START);
+
+// Maps a new kernel stack of `pages` pages and returns its top
+pub fn allocate_stack(pages: u64) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    // the guard page comes first, stacks grow down
+    let start = NEXT_STACK.fetch_add((pages + 1) * 4096, Ordering::Relaxed) + 4096;
+    let first_page = Page::<Size4KiB>::containing_address(VirtAddr::new(start));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+    with_mapper(|mapper, frame_allocator| {
+        for page in Page::range(first_page, first_page + pages) {
+            let frame = frame_allocator.allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
+            unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+        }
+        Ok(VirtAddr::new(start + pages * 4096))
+    }).unwrap_or(Err(MapToError::FrameAllocationFailed))
+}
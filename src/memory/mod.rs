@@ -1,4 +1,8 @@
 pub mod gdt;
 pub mod interrupts;
+pub mod irq;
+pub mod fpu;
+pub mod syscall;
+pub mod usermode;
 pub mod memory;
 pub mod allocator;
@@ -0,0 +1,270 @@
+// System calls
+//
+// There are two ways into the kernel:
+// - The SYSCALL instruction, the fast path. The CPU loads CS and SS from the STAR MSR and RIP from
+//   LSTAR, saves RIP in rcx and RFLAGS in r11, and clears the RFLAGS bits set in SFMASK. It doesn't
+//   switch stacks, so the entry stub does that itself. SYSRET always returns to ring 3.
+// - `int 0x80`, kept for debugging. It's slower, but works from ring 0 too and preserves rcx and
+//   r11.
+//
+// Both use the Linux register convention:
+//
+// Register  Use
+// rax       syscall number in, result out
+// rdi       argument 1
+// rsi       argument 2
+// rdx       argument 3
+// r10       argument 4 (rcx is taken by SYSCALL)
+// r8        argument 5
+// r9        argument 6
+//
+// Errors are returned as negative numbers, see SyscallError. Every other register is preserved,
+// except rcx and r11 with SYSCALL.
+//
+// Number  Name    Arguments        Result
+// 0       write   pointer, length  bytes written to the console
+// 1       uptime  -                nanoseconds since boot
+// 2       exit    code             doesn't return, `usermode::run_user` returns the code
+//
+// Syscalls run with interrupts disabled, both entries clear IF.
+
+use core::{arch::global_asm, str};
+use x86_64::{
+    registers::{
+        model_specific::{Efer, EferFlags, LStar, SFMask, Star},
+        rflags::RFlags,
+    },
+    structures::idt::InterruptDescriptorTable,
+    PrivilegeLevel,
+    VirtAddr,
+};
+use crate::{print, memory::{gdt, memory::is_user_accessible, usermode}, time};
+
+pub const WRITE: u64 = 0;
+pub const UPTIME: u64 = 1;
+pub const EXIT: u64 = 2;
+
+pub const INT_VECTOR: usize = 0x80;
+// End of the lower half, user pointers have to stay below it
+const USER_END: u64 = 0x0000_8000_0000_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum SyscallError {
+    UnknownSyscall = 1,
+    BadAddress = 2,
+    InvalidArgument = 3,
+}
+impl SyscallError {
+    // Decodes the value a syscall left in rax
+    pub fn from_result(result: u64) -> Result<u64, SyscallError> {
+        match result as i64 {
+            -1 => Err(SyscallError::UnknownSyscall),
+            -2 => Err(SyscallError::BadAddress),
+            -3 => Err(SyscallError::InvalidArgument),
+            _ => Ok(result),
+        }
+    }
+}
+
+// The registers the entry stubs push. With SYSCALL, rcx and r11 hold the user RIP and RFLAGS.
+#[derive(Debug)]
+#[repr(C)]
+pub struct SyscallFrame {
+    pub rax: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub r10: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r11: u64,
+    pub rcx: u64,
+}
+impl SyscallFrame {
+    pub fn args(&self) -> [u64; 6] {
+        [self.rdi, self.rsi, self.rdx, self.r10, self.r8, self.r9]
+    }
+}
+
+type Handler = fn(args: [u64; 6]) -> Result<u64, SyscallError>;
+
+// Indexed by syscall number
+static TABLE: [Handler; 3] = [
+    sys_write,
+    sys_uptime,
+    sys_exit,
+];
+
+// The stack SYSCALL switches to. The user RSP is parked in SYSCALL_USER_RSP meanwhile, which is
+// fine as long as syscalls can't be interrupted.
+const STACK_SIZE: usize = 4096 * 5;
+// SYSCALL doesn't align RSP like interrupt delivery does, the stack top has to be aligned already
+#[repr(C, align(16))]
+struct Stack([u8; STACK_SIZE]);
+static mut STACK: Stack = Stack([0; STACK_SIZE]);
+#[no_mangle]
+static mut SYSCALL_KERNEL_RSP: u64 = 0;
+#[no_mangle]
+static mut SYSCALL_USER_RSP: u64 = 0;
+
+extern "C" {
+    fn syscall_entry();
+    fn syscall_int80_entry();
+}
+
+// The frame is built on the stack in reverse field order, so `rsp` points to a SyscallFrame. Both
+// stubs leave the stack 16 byte aligned for the call: SYSCALL pushes 10 registers onto an aligned
+// stack, and `int` aligns the stack before pushing its 5 qword frame.
+global_asm!(r#"
+.global syscall_entry
+syscall_entry:
+    mov [rip + SYSCALL_USER_RSP], rsp
+    mov rsp, [rip + SYSCALL_KERNEL_RSP]
+    push qword ptr [rip + SYSCALL_USER_RSP]
+    push rcx
+    push r11
+    push r9
+    push r8
+    push r10
+    push rdx
+    push rsi
+    push rdi
+    push rax
+    mov rdi, rsp
+    call syscall_dispatch
+    add rsp, 8
+    pop rdi
+    pop rsi
+    pop rdx
+    pop r10
+    pop r8
+    pop r9
+    pop r11
+    pop rcx
+    pop rsp
+    sysretq
+
+.global syscall_int80_entry
+syscall_int80_entry:
+    cld
+    push rcx
+    push r11
+    push r9
+    push r8
+    push r10
+    push rdx
+    push rsi
+    push rdi
+    push rax
+    mov rdi, rsp
+    call syscall_dispatch
+    add rsp, 8
+    pop rdi
+    pop rsi
+    pop rdx
+    pop r10
+    pop r8
+    pop r9
+    pop r11
+    pop rcx
+    iretq
+"#);
+
+// Enables SYSCALL/SYSRET. Must be called after gdt::init.
+pub fn init() {
+    let selectors = gdt::selectors();
+    Star::write(selectors.user_code, selectors.user_data, selectors.kernel_code, selectors.kernel_data)
+        .expect("GDT layout doesn't fit SYSCALL/SYSRET");
+    LStar::write(VirtAddr::new(syscall_entry as unsafe extern "C" fn() as u64));
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::DIRECTION_FLAG | RFlags::TRAP_FLAG);
+
+    unsafe {
+        SYSCALL_KERNEL_RSP = (VirtAddr::from_ptr(&STACK) + STACK_SIZE).as_u64();
+        Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS));
+    }
+}
+
+// The `int 0x80` gate, callable from ring 3
+pub(crate) fn set_idt_entry(idt: &mut InterruptDescriptorTable) {
+    unsafe {
+        idt[INT_VECTOR]
+            .set_handler_addr(VirtAddr::new(syscall_int80_entry as unsafe extern "C" fn() as u64))
+            .set_privilege_level(PrivilegeLevel::Ring3);
+    }
+}
+
+#[no_mangle]
+extern "C" fn syscall_dispatch(frame: &mut SyscallFrame) -> u64 {
+    let result = match TABLE.get(frame.rax as usize) {
+        Some(handler) => handler(frame.args()),
+        None => Err(SyscallError::UnknownSyscall),
+    };
+    match result {
+        Ok(value) => value,
+        Err(error) => -(error as i64) as u64,
+    }
+}
+
+// Checks that `pointer..pointer + length` lies in the lower half and that user code may read
+// every page of it. Being mapped isn't enough, the kernel is mapped too.
+fn user_slice(pointer: u64, length: u64) -> Result<&'static [u8], SyscallError> {
+    if length == 0 {
+        return Ok(&[]);
+    }
+    let end = pointer.checked_add(length).ok_or(SyscallError::BadAddress)?;
+    if end > USER_END {
+        return Err(SyscallError::BadAddress);
+    }
+    let start = VirtAddr::new(pointer);
+    if !is_user_accessible(start, length) {
+        return Err(SyscallError::BadAddress);
+    }
+    Ok(unsafe { core::slice::from_raw_parts(start.as_ptr(), length as usize) })
+}
+
+fn sys_write(args: [u64; 6]) -> Result<u64, SyscallError> {
+    let bytes = user_slice(args[0], args[1])?;
+    let text = str::from_utf8(bytes).map_err(|_| SyscallError::InvalidArgument)?;
+    print!("{}", text);
+    Ok(bytes.len() as u64)
+}
+
+fn sys_uptime(_args: [u64; 6]) -> Result<u64, SyscallError> {
+    Ok(time::uptime().as_nanos() as u64)
+}
+
+fn sys_exit(args: [u64; 6]) -> Result<u64, SyscallError> {
+    // from kernel code through `int 0x80` there's nothing to exit to
+    if !usermode::is_running() {
+        return Err(SyscallError::InvalidArgument);
+    }
+    usermode::exit(args[0])
+}
+
+// Tests
+#[cfg(test)]
+fn int80(number: u64, arg1: u64, arg2: u64) -> Result<u64, SyscallError> {
+    let result: u64;
+    unsafe {
+        core::arch::asm!("int 0x80", inlateout("rax") number => result, in("rdi") arg1, in("rsi") arg2);
+    }
+    SyscallError::from_result(result)
+}
+
+#[test_case]
+fn test_int80_syscalls() {
+    use x86_64::structures::paging::PageTableFlags;
+
+    let text = "int 0x80 ";
+    let buffer = VirtAddr::new(0x0000_6000_0000_0000);
+    super::memory::map_user(buffer, text.len() as u64, PageTableFlags::WRITABLE).unwrap();
+    unsafe { core::ptr::copy_nonoverlapping(text.as_ptr(), buffer.as_mut_ptr(), text.len()) };
+    assert_eq!(int80(WRITE, buffer.as_u64(), text.len() as u64), Ok(text.len() as u64));
+    // mapped, but kernel memory
+    assert_eq!(int80(WRITE, text.as_ptr() as u64, text.len() as u64), Err(SyscallError::BadAddress));
+    assert_eq!(int80(WRITE, 0, 16), Err(SyscallError::BadAddress));
+    assert_eq!(int80(WRITE, USER_END - 8, 16), Err(SyscallError::BadAddress));
+    assert!(int80(UPTIME, 0, 0).unwrap() > 0);
+    assert_eq!(int80(u64::MAX, 0, 0), Err(SyscallError::UnknownSyscall));
+}
@@ -0,0 +1,98 @@
+// Running code in ring 3
+//
+// `run_user` builds the frame an interrupt from ring 3 would have pushed and executes `iretq` on
+// it, which drops the privilege level and jumps to the entry point:
+//
+// Offset  Value
+// rsp+0   RIP     entry point
+// rsp+8   CS      user code selector
+// rsp+16  RFLAGS  interrupts enabled
+// rsp+24  RSP     user stack
+// rsp+32  SS      user data selector
+//
+// The user code comes back into the kernel through interrupts and syscalls, on the TSS privilege
+// stack or the SYSCALL stack. The `exit` syscall doesn't return to it: it throws away whatever
+// the kernel stack held and resumes `run_user` right after the `iretq`, with the callee-saved
+// registers, the stack and RFLAGS saved before entering ring 3.
+//
+// Every run gets its own FpuContext, the user code starts with clean FPU and SSE registers and
+// the kernel's are back after it exited, see memory::fpu.
+//
+// The code and its stack have to be mapped with USER_ACCESSIBLE, see memory::map_user.
+
+use core::{
+    arch::global_asm,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use x86_64::VirtAddr;
+use super::{fpu::{self, FpuContext}, gdt};
+
+static RUNNING: AtomicBool = AtomicBool::new(false);
+// Kernel RSP while user code runs, only valid between user_enter and user_exit
+#[no_mangle]
+static mut USER_RETURN_RSP: u64 = 0;
+
+extern "C" {
+    fn user_enter(entry: u64, stack: u64, code_selector: u64, data_selector: u64) -> u64;
+    fn user_exit(code: u64) -> !;
+}
+
+global_asm!(r#"
+.global user_enter
+user_enter:
+    pushfq
+    push rbx
+    push rbp
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [rip + USER_RETURN_RSP], rsp
+
+    push rcx
+    push rsi
+    push 0x202
+    push rdx
+    push rdi
+    iretq
+
+.global user_exit
+user_exit:
+    mov rsp, [rip + USER_RETURN_RSP]
+    mov rax, rdi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbp
+    pop rbx
+    popfq
+    ret
+"#);
+
+// Runs the code at `entry` in ring 3 with the stack pointer set to `stack`, until it calls the
+// `exit` syscall. Returns the exit code. Not reentrant, user code can't start more user code.
+//
+// Safety: `entry` and `stack` must point to memory mapped with USER_ACCESSIBLE, and the code must
+// stay away from the kernel's memory
+pub unsafe fn run_user(entry: VirtAddr, stack: VirtAddr) -> u64 {
+    let selectors = gdt::selectors();
+    assert!(!RUNNING.swap(true, Ordering::SeqCst), "user code is already running");
+    let mut context = FpuContext::new();
+    let code = fpu::with_context(&mut context, || {
+        user_enter(entry.as_u64(), stack.as_u64(), selectors.user_code.0 as u64, selectors.user_data.0 as u64)
+    });
+    RUNNING.store(false, Ordering::SeqCst);
+    code
+}
+
+// Whether there's a `run_user` to return to
+pub fn is_running() -> bool {
+    RUNNING.load(Ordering::SeqCst)
+}
+
+// Leaves the user code started by `run_user`, called by the `exit` syscall. Panics if there's none.
+pub(crate) fn exit(code: u64) -> ! {
+    assert!(is_running(), "exit without user code running");
+    unsafe { user_exit(code) }
+}
@@ -0,0 +1,132 @@
+// Shutdown and reboot.
+//
+// Shutdown uses ACPI: we write SLP_TYPa | SLP_EN into the PM1a control register (and SLP_TYPb
+// into PM1b if it exists), which puts the machine into the S5 (soft off) sleep state. If ACPI is
+// unavailable or doesn't work we fall back to the shutdown ports of the common emulators.
+//
+// Reboot tries, in order:
+//  * the ACPI reset register from the FADT
+//  * pulsing the reset line through the 8042 keyboard controller
+//  * a triple fault, by loading an empty IDT and raising an exception
+
+use x86_64::{
+    instructions::{interrupts, port::Port},
+    PhysAddr,
+};
+use crate::{
+    acpi::fadt::{self, AddressSpace, FadtInfo, GenericAddress},
+    hlt_loop,
+    memory::memory::phys_to_virt,
+    println,
+};
+
+// PM1 control register
+const SLP_EN: u16 = 1 << 13;
+const SCI_EN: u16 = 1 << 0;
+
+pub fn shutdown() -> ! {
+    println!("Shutting down...");
+    interrupts::disable();
+
+    if let Some(fadt) = fadt::get() {
+        acpi_shutdown(fadt);
+    }
+
+    unsafe {
+        // QEMU (newer versions), Bochs and older QEMU, VirtualBox
+        Port::<u16>::new(0x604).write(0x2000);
+        Port::<u16>::new(0xB004).write(0x2000);
+        Port::<u16>::new(0x4004).write(0x3400);
+    }
+
+    println!("Shutdown failed, it is now safe to turn off your computer");
+    hlt_loop();
+}
+
+pub fn reboot() -> ! {
+    println!("Rebooting...");
+    interrupts::disable();
+
+    if let Some((register, value)) = fadt::get().and_then(|fadt| fadt.reset) {
+        unsafe { write_generic_address(register, value) };
+    }
+
+    unsafe {
+        // wait until the input buffer of the 8042 is empty, then pulse the reset line
+        let mut status = Port::<u8>::new(0x64);
+        for _ in 0..0x10000 {
+            if status.read() & 0b10 == 0 {
+                break;
+            }
+        }
+        status.write(0xFE);
+    }
+
+    triple_fault();
+}
+
+fn acpi_shutdown(fadt: &FadtInfo) {
+    let (slp_typa, slp_typb) = match fadt.s5_sleep_types {
+        Some(types) => types,
+        None => return,
+    };
+    if fadt.pm1a_control_block == 0 {
+        return;
+    }
+
+    unsafe {
+        let mut pm1a_control = Port::<u16>::new(fadt.pm1a_control_block);
+
+        // hand the hardware over from SMM to ACPI if the firmware didn't do so already
+        if pm1a_control.read() & SCI_EN == 0 && fadt.smi_command != 0 && fadt.acpi_enable != 0 {
+            Port::<u8>::new(fadt.smi_command).write(fadt.acpi_enable);
+            for _ in 0..0x100000 {
+                if pm1a_control.read() & SCI_EN != 0 {
+                    break;
+                }
+            }
+        }
+
+        pm1a_control.write((slp_typa << 10) | SLP_EN);
+        if fadt.pm1b_control_block != 0 {
+            Port::<u16>::new(fadt.pm1b_control_block).write((slp_typb << 10) | SLP_EN);
+        }
+    }
+}
+
+// Writes `value` to the register described by a Generic Address Structure
+unsafe fn write_generic_address(register: GenericAddress, value: u8) {
+    let address = register.address;
+    match register.space() {
+        AddressSpace::SystemIo => Port::<u8>::new(address as u16).write(value),
+        AddressSpace::SystemMemory => {
+            let pointer = phys_to_virt(PhysAddr::new(address)).as_mut_ptr::<u8>();
+            core::ptr::write_volatile(pointer, value);
+        }
+        AddressSpace::PciConfig => {
+            // bus 0, device and function are encoded in the upper bits of the address
+            let device = ((address >> 32) & 0xFFFF) as u32;
+            let function = ((address >> 16) & 0xFFFF) as u32;
+            let offset = (address & 0xFFFF) as u32;
+            let config_address = 0x8000_0000 | (device << 11) | (function << 8) | (offset & 0xFC);
+            Port::<u32>::new(0xCF8).write(config_address);
+            Port::<u8>::new(0xCFC + (offset & 0b11) as u16).write(value);
+        }
+        AddressSpace::Other(_) => {}
+    }
+}
+
+// With an empty IDT every exception becomes a double fault, then a triple fault, which resets the
+// CPU.
+fn triple_fault() -> ! {
+    use x86_64::instructions::tables::{lidt, DescriptorTablePointer};
+    use x86_64::VirtAddr;
+
+    let empty = DescriptorTablePointer {
+        limit: 0,
+        base: VirtAddr::new(0),
+    };
+    unsafe { lidt(&empty) };
+    interrupts::int3();
+    hlt_loop();
+}
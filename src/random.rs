@@ -0,0 +1,251 @@
+// Kernel random numbers
+//
+// All random numbers come from a ChaCha20 based CSPRNG. Its 256 bit key is seeded, and reseeded
+// on every request, from whatever entropy the machine has:
+//
+// Source            Used when
+// RDSEED            CPUID reports it, straight from the hardware entropy source
+// RDRAND            CPUID reports it, a hardware DRBG that is reseeded by the same source
+// interrupt jitter  always mixed in, the only source without RDSEED and RDRAND
+//
+// Interrupt jitter is the TSC at every hardware interrupt. The low bits of the time an interrupt
+// arrives at depend on the device, the bus and the state of the CPU caches, none of which we can
+// predict. Without hardware help the first request waits until enough interrupts have been seen.
+//
+// ChaCha20 (RFC 8439) turns the key, a block counter and a nonce into 64 bytes of keystream per
+// block, the keystream is our output. After every request the next block becomes the new key
+// ("fast key erasure"), so whoever reads the state later can't reconstruct earlier output.
+
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+use crate::{cpu::{self, Feature}, println, sync::IrqMutex, time::tsc};
+
+static RNG: IrqMutex<ChaCha20Rng> = IrqMutex::new(ChaCha20Rng::new());
+static SEEDED: AtomicBool = AtomicBool::new(false);
+// Whether weak output was reported already
+static WARNED: AtomicBool = AtomicBool::new(false);
+
+// Interrupts to wait for before seeding from jitter alone
+const MIN_JITTER_EVENTS: u64 = 256;
+// RDRAND and RDSEED can fail when the hardware runs dry, Intel recommends 10 retries
+const RETRIES: usize = 10;
+// Bytes generated per lock of RNG, a large request lets interrupts in between the chunks
+const CHUNK_SIZE: usize = 1024;
+
+static JITTER_POOL: [AtomicU64; 4] = [const { AtomicU64::new(0) }; 4];
+// interrupts mixed into the pool since the last time it was used
+static JITTER_EVENTS: AtomicU64 = AtomicU64::new(0);
+
+// Called for every hardware interrupt
+pub fn add_interrupt_entropy(line: u8) {
+    let events = JITTER_EVENTS.fetch_add(1, Ordering::Relaxed);
+    let sample = (tsc::read() ^ ((line as u64) << 56)).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    let slot = &JITTER_POOL[events as usize % JITTER_POOL.len()];
+    // only the BSP gets interrupts, nobody else writes the slot in between
+    slot.store(slot.load(Ordering::Relaxed).rotate_left(23) ^ sample, Ordering::Relaxed);
+}
+
+fn rdrand() -> Option<u64> {
+    (0..RETRIES).find_map(|_| {
+        let (value, ok): (u64, u8);
+        unsafe { asm!("rdrand {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack)) };
+        if ok != 0 { Some(value) } else { None }
+    })
+}
+
+fn rdseed() -> Option<u64> {
+    (0..RETRIES).find_map(|_| {
+        let (value, ok): (u64, u8);
+        unsafe { asm!("rdseed {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack)) };
+        if ok != 0 { Some(value) } else { None }
+    })
+}
+
+// The best source available, for `rand` and friends
+pub fn source() -> &'static str {
+    if cpu::has(Feature::Rdseed) {
+        "rdseed"
+    } else if cpu::has(Feature::Rdrand) {
+        "rdrand"
+    } else {
+        "interrupt jitter"
+    }
+}
+
+// 256 bits of seed material and whether they came with enough entropy
+fn gather_entropy() -> ([u64; 4], bool) {
+    let mut seed = [0u64; 4];
+    let mut hardware = true;
+    for word in seed.iter_mut() {
+        let value = if cpu::has(Feature::Rdseed) {
+            rdseed().or_else(rdrand)
+        } else if cpu::has(Feature::Rdrand) {
+            rdrand()
+        } else {
+            None
+        };
+        hardware &= value.is_some();
+        *word = value.unwrap_or(0);
+    }
+
+    let events = JITTER_EVENTS.swap(0, Ordering::Relaxed);
+    for (word, slot) in seed.iter_mut().zip(JITTER_POOL.iter()) {
+        *word ^= slot.load(Ordering::Relaxed);
+    }
+    seed[0] ^= tsc::read();
+
+    (seed, hardware || events >= MIN_JITTER_EVENTS)
+}
+
+// Seed material for the next request and whether it came with enough entropy. Before the first
+// seeding and without hardware help it waits for jitter, as long as interrupts can actually
+// arrive. Can't be called with RNG locked, the lock keeps interrupts disabled.
+fn seed_material() -> ([u64; 4], bool) {
+    let (mut material, mut enough) = gather_entropy();
+    while !SEEDED.load(Ordering::Relaxed) && !enough && x86_64::instructions::interrupts::are_enabled() {
+        x86_64::instructions::hlt();
+        let (more, more_enough) = gather_entropy();
+        for (word, more) in material.iter_mut().zip(more) {
+            *word = word.rotate_left(17) ^ more;
+        }
+        enough = more_enough;
+    }
+    (material, enough)
+}
+
+// Before the first seeding with enough entropy, e.g. when called with interrupts disabled on a CPU
+// without RDRAND, the output is weak. That is reported once, and the next request tries again.
+pub fn fill_bytes(buffer: &mut [u8]) {
+    let (material, enough) = seed_material();
+    RNG.lock().reseed(&material);
+    if enough {
+        SEEDED.store(true, Ordering::Relaxed);
+    } else if !SEEDED.load(Ordering::Relaxed) && !WARNED.swap(true, Ordering::Relaxed) {
+        println!("WARNING: random numbers requested before enough entropy was gathered");
+    }
+    for chunk in buffer.chunks_mut(CHUNK_SIZE) {
+        RNG.lock().fill_bytes(chunk);
+    }
+}
+
+pub fn next_u64() -> u64 {
+    let mut bytes = [0; 8];
+    fill_bytes(&mut bytes);
+    u64::from_le_bytes(bytes)
+}
+
+// Uniformly distributed in `low..=high`
+pub fn range(low: u64, high: u64) -> u64 {
+    assert!(low <= high, "empty range");
+    let span = high - low;
+    if span == u64::MAX {
+        return next_u64();
+    }
+    // reject the top values that would make the low ones more likely
+    let span = span + 1;
+    let zone = u64::MAX - u64::MAX % span;
+    loop {
+        let value = next_u64();
+        if value < zone {
+            return low + value % span;
+        }
+    }
+}
+
+// ChaCha20
+
+const CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646E, 0x7962_2D32, 0x6B20_6574]; // "expand 32-byte k"
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]); state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]); state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]); state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]); state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+// One 64 byte keystream block
+fn chacha20_block(key: &[u32; 8], counter: u32, nonce: &[u32; 3]) -> [u8; 64] {
+    let mut initial = [0u32; 16];
+    initial[..4].copy_from_slice(&CONSTANTS);
+    initial[4..12].copy_from_slice(key);
+    initial[12] = counter;
+    initial[13..].copy_from_slice(nonce);
+
+    let mut state = initial;
+    // 20 rounds, every iteration is a column round and a diagonal round
+    for _ in 0..10 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+
+    let mut block = [0; 64];
+    for (i, bytes) in block.chunks_mut(4).enumerate() {
+        bytes.copy_from_slice(&state[i].wrapping_add(initial[i]).to_le_bytes());
+    }
+    block
+}
+
+struct ChaCha20Rng {
+    key: [u32; 8],
+}
+impl ChaCha20Rng {
+    const fn new() -> Self {
+        ChaCha20Rng { key: [0; 8] }
+    }
+
+    fn reseed(&mut self, material: &[u64; 4]) {
+        for (i, word) in material.iter().enumerate() {
+            self.key[2 * i] ^= *word as u32;
+            self.key[2 * i + 1] ^= (*word >> 32) as u32;
+        }
+        // don't keep the seed material around in the key
+        self.rekey(&chacha20_block(&self.key, 0, &[0; 3]));
+    }
+
+    fn rekey(&mut self, block: &[u8; 64]) {
+        for (word, bytes) in self.key.iter_mut().zip(block.chunks(4)) {
+            *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+    }
+
+    fn fill_bytes(&mut self, buffer: &mut [u8]) {
+        // block 0 becomes the next key, the output starts at block 1. The counter is 32 bits, so
+        // one key gives at most 256 GiB, we rekey long before that.
+        let mut counter = 1;
+        for chunk in buffer.chunks_mut(64) {
+            let block = chacha20_block(&self.key, counter, &[0; 3]);
+            chunk.copy_from_slice(&block[..chunk.len()]);
+            counter += 1;
+        }
+        self.rekey(&chacha20_block(&self.key, 0, &[0; 3]));
+    }
+}
+
+// Tests
+#[test_case]
+fn test_chacha20_block() {
+    // RFC 8439, section 2.3.2
+    let key: [u32; 8] = [0x0302_0100, 0x0706_0504, 0x0B0A_0908, 0x0F0E_0D0C, 0x1312_1110, 0x1716_1514, 0x1B1A_1918, 0x1F1E_1D1C];
+    let nonce: [u32; 3] = [0x0900_0000, 0x4A00_0000, 0x0000_0000];
+    let block = chacha20_block(&key, 1, &nonce);
+    assert_eq!(block[..16], [0x10, 0xF1, 0xE7, 0xE4, 0xD1, 0x3B, 0x59, 0x15, 0x50, 0x0F, 0xDD, 0x1F, 0xA3, 0x20, 0x71, 0xC4]);
+    assert_eq!(block[60..], [0xA2, 0x50, 0x3C, 0x4E]);
+}
+
+#[test_case]
+fn test_random_range() {
+    for _ in 0..100 {
+        let value = range(10, 20);
+        assert!((10..=20).contains(&value));
+    }
+    assert_eq!(range(7, 7), 7);
+    assert_ne!(next_u64(), next_u64());
+}
@@ -1,102 +1,132 @@
-use alloc::{string::{ToString, String}, vec::Vec, format};
+use alloc::{string::String, vec::Vec, format};
+use core::time::Duration;
+use futures_util::stream::StreamExt;
 use pc_keyboard::DecodedKey;
-use crate::{print, println, io::vga_buffer::{WRITER, writer::BUFFER_HEIGHT}};
+use crate::{
+    print,
+    println,
+    io::{ps2, vga_buffer::{WRITER, writer::BUFFER_HEIGHT}},
+    memory::irq,
+    power,
+    random,
+    sync::IrqMutex,
+    task::{executor, keyboard::KeyEventStream, timer},
+    time,
+};
 
-use super::{exit_qemu, QemuExitCode};
+// Largest `rand bytes` request, the bytes are printed as hex anyway
+const MAX_RAND_BYTES: usize = 4096;
 
-static mut COMMAND_DRAFT: String = String::new();
-static mut COMMAND: String = String::new();
-static mut HISTORY: Vec<String> = Vec::new();
-static mut POINT: usize = 0;
-
-fn check_or_add() {
-    unsafe {
-        match HISTORY.get(POINT) {
-            Some(_) => (),
-            None => {
-                HISTORY.push(String::new());
-                ()
-            }
+// What is typed so far and the previous commands, the newest first. `point` is the history entry
+// the draft belongs to.
+struct State {
+    draft: String,
+    history: Vec<String>,
+    point: usize,
+}
+impl State {
+    fn check_or_add(&mut self) {
+        if self.history.get(self.point).is_none() {
+            self.history.push(String::new());
         }
     }
-}
 
-fn update_history() {
-    unsafe {
-        match HISTORY.get(POINT) {
-            Some(_) => HISTORY[POINT] = COMMAND_DRAFT.clone(),
-            None => ()
+    fn update_history(&mut self) {
+        if let Some(entry) = self.history.get_mut(self.point) {
+            *entry = self.draft.clone();
         }
     }
 }
 
+static STATE: IrqMutex<State> = IrqMutex::new(State {
+    draft: String::new(),
+    history: Vec::new(),
+    point: 0,
+});
+
 fn update_display() {
     WRITER.lock().clear_row(BUFFER_HEIGHT-1);
-    unsafe { print!("\n> {}", COMMAND_DRAFT); }
+    print!("\n> {}", STATE.lock().draft);
+}
+
+// The shell task, spawned on the executor by kernel_main with Priority::Input. Key presses come
+// from the keyboard and the serial console alike, see task::serial. Commands run inside the task,
+// one that waits lets the other tasks run in the meantime and a long one calls `cooperate`.
+pub async fn run_shell() {
+    let mut events = KeyEventStream::subscribe().expect("no free keyboard subscriber slot");
+
+    print!("> ");
+    while let Some(event) = events.next().await {
+        // only presses of non-modifier keys carry a key
+        if let Some(key) = event.key {
+            get_char(key).await;
+        }
+    }
 }
 
-pub fn get_char(key: DecodedKey) {
-    check_or_add();
+async fn get_char(key: DecodedKey) {
+    STATE.lock().check_or_add();
     match key {
         DecodedKey::Unicode(character) => {
             if character == '\n' {
-                unsafe {
-                    let topush = COMMAND_DRAFT.clone();
+                let command = {
+                    let mut state = STATE.lock();
+                    let command = core::mem::take(&mut state.draft);
 
-                    COMMAND = COMMAND_DRAFT.to_string();
-                    COMMAND_DRAFT = String::new();
-                    
                     // update history
-                    HISTORY.remove(POINT);
-                    HISTORY.insert(0, topush);
-                    POINT = 0;
+                    let point = state.point;
+                    state.history.remove(point);
+                    state.history.insert(0, command.clone());
+                    state.point = 0;
+                    command
                 };
 
                 println!();
-                run();
+                run(&command).await;
                 print!("\n> ");
             } else if character == '\x08' {
                 // \x08 -> \b
                 // rust doesn't support \b directly
-                unsafe { COMMAND_DRAFT.pop(); }
+                STATE.lock().draft.pop();
 
                 update_display();
             } else {
-                unsafe { COMMAND_DRAFT += character.to_string().as_str(); };
+                STATE.lock().draft.push(character);
                 print!("{}", character);
             }
         }
         DecodedKey::RawKey(rawkey) => {
             let name = format!("{:?}", rawkey);
-            unsafe {
-            if name == "ArrowUp" {
-                    if POINT == 0 {
+            {
+                let mut state = STATE.lock();
+                if name == "ArrowUp" {
+                    if state.point == 0 {
                         return;
                     }
 
-                    POINT -= 1;
-                    COMMAND_DRAFT = HISTORY[POINT].clone();
-            } else if name == "ArrowDown" {
-                if COMMAND_DRAFT == "" {
-                    return;
+                    state.point -= 1;
+                    state.draft = state.history[state.point].clone();
+                } else if name == "ArrowDown" {
+                    if state.draft == "" {
+                        return;
+                    }
+                    state.draft = state.history[state.point].clone(); // save the old draft
+                    state.point += 1;
+                    state.check_or_add();
+                    state.draft = state.history[state.point].clone(); // load the new draft
                 }
-                COMMAND_DRAFT = HISTORY[POINT].clone(); // save the old draft
-                POINT += 1;
-                check_or_add();
-                COMMAND_DRAFT = HISTORY[POINT].clone(); // load the new draft
-            }
             }
 
             update_display();
         },
     }
 
-    update_history();
+    STATE.lock().update_history();
 }
 
-fn run() {
+async fn run(line: &str) {
     let (command, args): (String, Vec<&str>) =  {
-        let mut temp = unsafe { COMMAND.split(' ') };
+        let mut temp = line.split(' ');
         let cmd = match temp.next() {
             Some(i) => i,
             None => ""
@@ -119,18 +149,180 @@ fn run() {
     };
 
     if command == "" { // do nothing
-    } else if command == "exit" {
-        exit_qemu(QemuExitCode::Success); // ! not working
-        super::hlt_loop();
+    } else if command == "shutdown" || command == "exit" {
+        power::shutdown();
+    } else if command == "reboot" {
+        power::reboot();
     } else if command == "clear" {
         for i in 1..BUFFER_HEIGHT {
             WRITER.lock().clear_row(i);
         }
     }else if command == "echo" {
         println!("{}", args.join(" "));
+    } else if command == "sleep" {
+        match args.first().and_then(|millis| millis.parse().ok()) {
+            Some(millis) => timer::sleep(Duration::from_millis(millis)).await,
+            None => println!("usage: sleep <milliseconds>"),
+        }
+    } else if command == "uptime" {
+        let uptime = time::uptime();
+        let seconds = uptime.as_secs();
+        println!(
+            "up {}:{:02}:{:02}.{:03} ({} ticks at {} Hz)",
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60,
+            uptime.subsec_millis(),
+            time::ticks(),
+            time::frequency(),
+        );
+    } else if command == "clocksource" {
+        let source = time::clocksource();
+        let frequency = time::clocksource_frequency();
+        println!(
+            "{} at {}.{:06} MHz{}",
+            source.name(),
+            frequency / 1_000_000,
+            frequency % 1_000_000,
+            if time::tsc::is_invariant() { " (invariant tsc)" } else { "" },
+        );
+        println!("tick: {} at {} Hz", time::tick_source(), time::frequency());
+    } else if command == "date" {
+        if args.is_empty() {
+            println!("{}", time::SystemTime::now().to_datetime());
+        } else if args[0] == "set" && args.len() == 3 {
+            match time::DateTime::parse(args[1], args[2]) {
+                Some(datetime) => match time::system_time::set(datetime) {
+                    Ok(()) => println!("{}", datetime),
+                    Err(error) => println!("date: could not set the RTC ({:?})", error),
+                },
+                None => println!("date: invalid date, expected YYYY-MM-DD HH:MM:SS"),
+            }
+        } else {
+            println!("usage: date [set YYYY-MM-DD HH:MM:SS]");
+        }
+    } else if command == "irqstat" {
+        println!("IRQ       count  unhandled  spurious  masked  handlers");
+        for line in 0..irq::IRQ_LINES as u8 {
+            let names: Vec<&str> = irq::handlers(line).map(|action| action.name).collect();
+            if irq::count(line) == 0 && irq::spurious(line) == 0 && names.is_empty() {
+                continue;
+            }
+            println!(
+                "{:>3}  {:>10}  {:>9}  {:>8}  {:>6}  {}",
+                line,
+                irq::count(line),
+                irq::unhandled(line),
+                irq::spurious(line),
+                if irq::is_masked(line) { "yes" } else { "no" },
+                names.join(", "),
+            );
+        }
+    } else if command == "cpuinfo" {
+        use crate::cpu::{self, Feature};
+
+        let info = cpu::info();
+        println!("vendor: {}", info.vendor());
+        println!("model name: {}", if info.brand().is_empty() { "unknown" } else { info.brand() });
+        println!("family {} model {} stepping {}", info.family, info.model, info.stepping);
+        let flags: Vec<&str> = Feature::ALL.iter().filter(|feature| info.has(**feature)).map(|feature| feature.name()).collect();
+        println!("flags: {}", flags.join(" "));
+    } else if command == "cpus" {
+        use crate::smp;
+
+        println!("CPU  APIC  state");
+        for cpu in smp::cpus() {
+            println!(
+                "{:>3}  {:>4}  {}{}{}",
+                cpu.index(),
+                cpu.apic_id(),
+                cpu.state().name(),
+                if cpu.is_bootstrap() { " (bsp)" } else { "" },
+                if core::ptr::eq(cpu, smp::current()) { " *" } else { "" },
+            );
+        }
+    } else if command == "watchdog" {
+        use crate::smp::watchdog;
+
+        match args.first().copied() {
+            None if watchdog::is_running() => println!(
+                "running, threshold {} ms, {} stalls reported",
+                watchdog::threshold().as_millis(),
+                watchdog::reports()
+            ),
+            None => println!("not running"),
+            // hangs with interrupts disabled until the watchdog notices
+            Some("test") if watchdog::is_running() => {
+                let reports = watchdog::reports();
+                x86_64::instructions::interrupts::without_interrupts(|| {
+                    while watchdog::reports() == reports {
+                        core::hint::spin_loop();
+                    }
+                });
+            }
+            Some("test") => println!("watchdog: not running"),
+            _ => println!("usage: watchdog [test]"),
+        }
+    } else if command == "keymap" {
+        use crate::task::keymap::{self, Layout, ScancodeSet};
+
+        match args.first().copied() {
+            None => {
+                let layouts: Vec<&str> = Layout::ALL.iter().map(|layout| layout.name()).collect();
+                println!("layout: {}, scancode set {}", keymap::layout().name(), keymap::scancode_set() as u8);
+                println!("available: {}, set1, set2", layouts.join(", "));
+            }
+            Some(set @ ("set1" | "set2")) => {
+                // the controller has to stop (or start) translating to set 1 as well
+                let set1 = set == "set1";
+                if ps2::set_translation(set1).is_err() {
+                    keymap::set_scancode_set(if set1 { ScancodeSet::Set1 } else { ScancodeSet::Set2 });
+                }
+                keymap::save();
+            }
+            Some(name) => match Layout::from_name(name) {
+                Some(layout) => {
+                    keymap::set_layout(layout);
+                    keymap::save();
+                }
+                None => println!("keymap: unknown layout {}", name),
+            },
+        }
+    } else if command == "kbdrate" {
+        let delay = args.get(0).and_then(|delay| delay.parse().ok());
+        let rate = args.get(1).and_then(|rate| rate.parse().ok());
+        match (delay, rate) {
+            (Some(delay), Some(rate)) => {
+                if let Err(error) = ps2::set_typematic(delay, rate) {
+                    println!("kbdrate: {:?}", error);
+                }
+            }
+            _ => println!("usage: kbdrate <delay in ms> <repeats per second>"),
+        }
     } else if command == "rand" {
-        // let rand = x86_64::instructions::random::RdRand(());
-        // println!("{:?}", rand);
+        match args.as_slice() {
+            [] => println!("{}", random::next_u64()),
+            ["bytes", count] => match count.parse::<usize>() {
+                Ok(count) if count > MAX_RAND_BYTES => println!("rand: at most {} bytes", MAX_RAND_BYTES),
+                Ok(count) => {
+                    let mut bytes = alloc::vec![0u8; count];
+                    random::fill_bytes(&mut bytes);
+                    for chunk in bytes.chunks(32) {
+                        let hex: String = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+                        print!("{}", hex);
+                        executor::cooperate().await;
+                    }
+                    println!();
+                }
+                Err(_) => println!("rand: invalid byte count {}", count),
+            },
+            [low, high] => match (low.parse::<u64>(), high.parse::<u64>()) {
+                (Ok(low), Ok(high)) if low <= high => println!("{}", random::range(low, high)),
+                _ => println!("rand: invalid range {} {}", low, high),
+            },
+            ["source"] => println!("{}", random::source()),
+            _ => println!("usage: rand [<min> <max> | bytes <count> | source]"),
+        }
     } else {
         println!("{} is not a command", command);
     }
@@ -0,0 +1,166 @@
+// Local APIC
+//
+// Every CPU has its own local APIC, they all sit at the same physical address (0xFEE00000 unless
+// the MADT says otherwise) and each CPU sees its own one there. We still get our IRQs from the
+// 8259 PICs, the local APIC is used to send inter-processor interrupts (IPIs) and as the timer of
+// the lockup detector, see smp::watchdog.
+//
+// Offset  Register
+// 0x020   APIC id (bits 24-31)
+// 0x030   version
+// 0x0B0   end of interrupt
+// 0x0F0   spurious interrupt vector, bit 8 enables the APIC
+// 0x300   interrupt command, low half (vector, delivery mode, status)
+// 0x310   interrupt command, high half (destination APIC id in bits 24-31)
+// 0x320   timer entry of the local vector table (vector, mask, periodic)
+// 0x380   timer initial count
+// 0x390   timer current count
+// 0x3E0   timer divide configuration
+//
+// Writing the low half of the interrupt command register sends the IPI, bit 12 stays set until
+// it was delivered. The timer counts down from the initial count at the bus clock divided by the
+// divide configuration, that rate isn't reported anywhere and has to be measured.
+//
+// After INIT the APIC is software disabled: it still takes INIT, STARTUP and NMI IPIs, but fixed
+// interrupts only after `enable`.
+
+use core::{
+    hint::spin_loop,
+    ptr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use x86_64::{
+    structures::{
+        idt::{InterruptDescriptorTable, InterruptStackFrame},
+        paging::{mapper::MapToError, Size4KiB},
+    },
+    PhysAddr,
+};
+use crate::{memory::memory::map_mmio, time::Instant};
+
+const ID: usize = 0x020;
+const VERSION: usize = 0x030;
+const EOI: usize = 0x0B0;
+const SPURIOUS: usize = 0x0F0;
+const ICR_LOW: usize = 0x300;
+const ICR_HIGH: usize = 0x310;
+const LVT_TIMER: usize = 0x320;
+const TIMER_INITIAL: usize = 0x380;
+const TIMER_CURRENT: usize = 0x390;
+const TIMER_DIVIDE: usize = 0x3E0;
+
+// Interrupt command bits
+const DELIVERY_NMI: u32 = 0b100 << 8;
+const DELIVERY_INIT: u32 = 0b101 << 8;
+const DELIVERY_STARTUP: u32 = 0b110 << 8;
+const DELIVERY_PENDING: u32 = 1 << 12;
+const LEVEL_ASSERT: u32 = 1 << 14;
+
+const SOFTWARE_ENABLE: u32 = 1 << 8;
+const TIMER_MASKED: u32 = 1 << 16;
+const TIMER_PERIODIC: u32 = 1 << 17;
+const DIVIDE_BY_16: u32 = 0b0011;
+const CALIBRATION_TIME: Duration = Duration::from_millis(10);
+
+// Raised instead of an interrupt that went away before the CPU took it, doesn't take an EOI
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+// Virtual address of the registers, 0 until `init`
+static BASE: AtomicU64 = AtomicU64::new(0);
+
+pub fn init(address: PhysAddr) -> Result<(), MapToError<Size4KiB>> {
+    let base = map_mmio(address, 4096)?;
+    BASE.store(base.as_u64(), Ordering::Relaxed);
+    Ok(())
+}
+
+pub fn is_initialized() -> bool {
+    BASE.load(Ordering::Relaxed) != 0
+}
+
+fn read(register: usize) -> u32 {
+    let base = BASE.load(Ordering::Relaxed);
+    assert!(base != 0, "local APIC not initialized");
+    unsafe { ptr::read_volatile((base as usize + register) as *const u32) }
+}
+
+fn write(register: usize, value: u32) {
+    let base = BASE.load(Ordering::Relaxed);
+    assert!(base != 0, "local APIC not initialized");
+    unsafe { ptr::write_volatile((base as usize + register) as *mut u32, value) }
+}
+
+// APIC id of the CPU we are running on
+pub fn id() -> u32 {
+    read(ID) >> 24
+}
+
+pub fn version() -> u8 {
+    read(VERSION) as u8
+}
+
+fn send_ipi(apic_id: u32, command: u32) {
+    write(ICR_HIGH, apic_id << 24);
+    write(ICR_LOW, command);
+    while read(ICR_LOW) & DELIVERY_PENDING != 0 {
+        spin_loop();
+    }
+}
+
+// Sends `vector` to another CPU, its APIC has to be enabled
+pub fn send_fixed(apic_id: u32, vector: u8) {
+    send_ipi(apic_id, LEVEL_ASSERT | vector as u32);
+}
+
+pub fn send_nmi(apic_id: u32) {
+    send_ipi(apic_id, DELIVERY_NMI | LEVEL_ASSERT);
+}
+
+// Resets the CPU into its wait-for-SIPI state
+pub fn send_init(apic_id: u32) {
+    send_ipi(apic_id, DELIVERY_INIT | LEVEL_ASSERT);
+}
+
+// Starts a CPU waiting for a SIPI in real mode at `page` * 4096
+pub fn send_startup(apic_id: u32, page: u8) {
+    send_ipi(apic_id, DELIVERY_STARTUP | LEVEL_ASSERT | page as u32);
+}
+
+// Software enables the local APIC of the CPU we are running on
+pub fn enable() {
+    write(SPURIOUS, SOFTWARE_ENABLE | SPURIOUS_VECTOR as u32);
+}
+
+// Every handler of an interrupt from the local APIC has to call this
+pub fn end_of_interrupt() {
+    write(EOI, 0);
+}
+
+// Timer counts per second, measured against `Instant`. Spins for 10 ms.
+pub fn calibrate_timer() -> u64 {
+    write(TIMER_DIVIDE, DIVIDE_BY_16);
+    write(LVT_TIMER, TIMER_MASKED);
+    write(TIMER_INITIAL, u32::MAX);
+    let start = Instant::now();
+    while start.elapsed() < CALIBRATION_TIME {
+        spin_loop();
+    }
+    let counted = u32::MAX - read(TIMER_CURRENT);
+    let elapsed = start.elapsed();
+    write(TIMER_INITIAL, 0);
+    counted as u64 * 1_000_000_000 / elapsed.as_nanos() as u64
+}
+
+// Raises `vector` every `count` timer counts, see `calibrate_timer`
+pub fn start_periodic_timer(vector: u8, count: u32) {
+    write(TIMER_DIVIDE, DIVIDE_BY_16);
+    write(LVT_TIMER, TIMER_PERIODIC | vector as u32);
+    write(TIMER_INITIAL, count);
+}
+
+extern "x86-interrupt" fn spurious_handler(_stack_frame: InterruptStackFrame) {}
+
+pub(crate) fn set_idt_entry(idt: &mut InterruptDescriptorTable) {
+    idt[SPURIOUS_VECTOR as usize].set_handler_fn(spurious_handler);
+}
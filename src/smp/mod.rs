@@ -0,0 +1,246 @@
+// Symmetric multiprocessing
+//
+// The firmware only starts one CPU, the bootstrap processor (BSP). The others, the application
+// processors (APs), wait until the BSP sends them an INIT IPI followed by up to two STARTUP IPIs
+// (SIPI). The list of APs comes from the MADT. A SIPI starts the AP in real mode, from where the
+// trampoline takes it to long mode and into `ap_entry`, see smp::trampoline. The APs are started
+// one after another and share the trampoline.
+//
+// State     Meaning
+// offline   listed in the MADT but not started, or CometOS never got that far
+// starting  IPIs sent, waiting for the AP to report in
+// online    running kernel code
+// failed    didn't report in within a second
+//
+// Each CPU gets its own GDT and TSS with its own double fault, NMI and privilege stacks, and its
+// GS base points to its entry in CPUS, see `current`.
+//
+// The PICs only ever interrupt the BSP, so for now the APs sit in a `hlt` loop once they're up.
+// The first one also runs the lockup detector, see smp::watchdog.
+// Syscalls and user mode also stay on the BSP, they share a single kernel stack.
+
+use core::{
+    hint::spin_loop,
+    sync::atomic::{AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+    time::Duration,
+};
+use x86_64::{
+    registers::model_specific::GsBase,
+    structures::paging::{mapper::MapToError, Size4KiB},
+    VirtAddr,
+};
+use crate::{
+    acpi::{madt, AcpiError},
+    cpu::{self, Feature},
+    hlt_loop,
+    memory::{fpu, gdt, interrupts, memory::allocate_stack},
+    time::Instant,
+};
+
+pub mod apic;
+pub mod trampoline;
+pub mod watchdog;
+
+pub const MAX_CPUS: usize = 16;
+
+// Stack sizes in pages
+const KERNEL_STACK_PAGES: u64 = 4;
+const DOUBLE_FAULT_STACK_PAGES: u64 = 5;
+const NMI_STACK_PAGES: u64 = 5;
+const PRIVILEGE_STACK_PAGES: u64 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CpuState {
+    Offline,
+    Starting,
+    Online,
+    Failed,
+}
+impl CpuState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CpuState::Offline => "offline",
+            CpuState::Starting => "starting",
+            CpuState::Online => "online",
+            CpuState::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SmpError {
+    NoApic,
+    Acpi(AcpiError),
+    Mapping(MapToError<Size4KiB>),
+    // CR3 points above 4 GiB, the trampoline can't load it in protected mode
+    PageTableUnreachable,
+}
+
+// Per-CPU data, GS base points to the entry of the CPU we are running on
+pub struct Cpu {
+    apic_id: AtomicU32,
+    state: AtomicU8,
+    // tops of the stacks for the TSS of an AP, mapped by the BSP before starting it
+    double_fault_stack: AtomicU64,
+    nmi_stack: AtomicU64,
+    privilege_stack: AtomicU64,
+}
+impl Cpu {
+    pub fn index(&'static self) -> usize {
+        unsafe { (self as *const Cpu).offset_from(CPUS.as_ptr()) as usize }
+    }
+
+    pub fn apic_id(&self) -> u32 {
+        self.apic_id.load(Ordering::Relaxed)
+    }
+
+    pub fn state(&self) -> CpuState {
+        match self.state.load(Ordering::Acquire) {
+            1 => CpuState::Starting,
+            2 => CpuState::Online,
+            3 => CpuState::Failed,
+            _ => CpuState::Offline,
+        }
+    }
+
+    fn set_state(&self, state: CpuState) {
+        self.state.store(state as u8, Ordering::Release);
+    }
+
+    pub fn is_bootstrap(&'static self) -> bool {
+        self.index() == 0
+    }
+}
+
+// The BSP is always CPUS[0]
+static CPUS: [Cpu; MAX_CPUS] = [const {
+    Cpu {
+        apic_id: AtomicU32::new(0),
+        state: AtomicU8::new(CpuState::Offline as u8),
+        double_fault_stack: AtomicU64::new(0),
+        nmi_stack: AtomicU64::new(0),
+        privilege_stack: AtomicU64::new(0),
+    }
+}; MAX_CPUS];
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(1);
+
+// The CPU we are running on. Before `init` that's the BSP, nothing else runs yet.
+pub fn current() -> &'static Cpu {
+    let base = GsBase::read();
+    if base.is_null() {
+        &CPUS[0]
+    } else {
+        unsafe { &*base.as_ptr() }
+    }
+}
+
+// Every CPU we know of, the BSP first
+pub fn cpus() -> impl Iterator<Item = &'static Cpu> {
+    CPUS[..CPU_COUNT.load(Ordering::Acquire)].iter()
+}
+
+pub fn online_count() -> usize {
+    cpus().filter(|cpu| cpu.state() == CpuState::Online).count()
+}
+
+// Starts every AP listed in the MADT and returns the number of CPUs online. Must be called after
+// `acpi::init` and `memory::memory::install`, and only once.
+pub fn init() -> Result<usize, SmpError> {
+    if !cpu::has(Feature::Apic) {
+        return Err(SmpError::NoApic);
+    }
+    let info = madt::find().map_err(SmpError::Acpi)?;
+    apic::init(info.local_apic_address).map_err(SmpError::Mapping)?;
+
+    let bsp = &CPUS[0];
+    bsp.apic_id.store(apic::id(), Ordering::Relaxed);
+    bsp.set_state(CpuState::Online);
+    GsBase::write(VirtAddr::from_ptr(bsp));
+
+    // xAPIC mode can only address ids below 255, 255 itself is the broadcast
+    let mut aps = madt::processors()
+        .filter(|processor| processor.enabled && processor.apic_id != bsp.apic_id() && processor.apic_id < 255)
+        .take(MAX_CPUS - 1)
+        .peekable();
+    if aps.peek().is_none() {
+        return Ok(1);
+    }
+
+    if !trampoline::page_table_reachable() {
+        return Err(SmpError::PageTableUnreachable);
+    }
+    trampoline::install().map_err(SmpError::Mapping)?;
+
+    for processor in aps {
+        let index = CPU_COUNT.load(Ordering::Relaxed);
+        let cpu = &CPUS[index];
+        cpu.apic_id.store(processor.apic_id, Ordering::Relaxed);
+        CPU_COUNT.store(index + 1, Ordering::Release);
+        start(index, cpu)?;
+    }
+
+    // an AP that didn't report in might still be on its way through the trampoline
+    if cpus().all(|cpu| cpu.state() == CpuState::Online) {
+        let _ = trampoline::remove();
+    }
+    Ok(online_count())
+}
+
+fn start(index: usize, cpu: &'static Cpu) -> Result<(), SmpError> {
+    let double_fault_stack = allocate_stack(DOUBLE_FAULT_STACK_PAGES).map_err(SmpError::Mapping)?;
+    let nmi_stack = allocate_stack(NMI_STACK_PAGES).map_err(SmpError::Mapping)?;
+    let privilege_stack = allocate_stack(PRIVILEGE_STACK_PAGES).map_err(SmpError::Mapping)?;
+    let stack = allocate_stack(KERNEL_STACK_PAGES).map_err(SmpError::Mapping)?;
+    cpu.double_fault_stack.store(double_fault_stack.as_u64(), Ordering::Relaxed);
+    cpu.nmi_stack.store(nmi_stack.as_u64(), Ordering::Relaxed);
+    cpu.privilege_stack.store(privilege_stack.as_u64(), Ordering::Relaxed);
+    trampoline::prepare(stack, ap_entry, index as u64);
+    cpu.set_state(CpuState::Starting);
+
+    // the delays are the ones from the MultiProcessor Specification
+    apic::send_init(cpu.apic_id());
+    wait(Duration::from_millis(10), || false);
+    apic::send_startup(cpu.apic_id(), trampoline::PAGE);
+    let online = || cpu.state() == CpuState::Online;
+    if !wait(Duration::from_micros(200), online) {
+        // a SIPI to an AP that already started is ignored
+        apic::send_startup(cpu.apic_id(), trampoline::PAGE);
+    }
+    if !wait(Duration::from_secs(1), online) {
+        cpu.set_state(CpuState::Failed);
+    }
+    Ok(())
+}
+
+// Spins until `done` returns true or `timeout` passed, returns whether `done` did
+fn wait(timeout: Duration, done: impl Fn() -> bool) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        if done() {
+            return true;
+        }
+        spin_loop();
+    }
+    done()
+}
+
+// Where the trampoline jumps to, on the stack from `start`
+extern "C" fn ap_entry(index: u64) -> ! {
+    let cpu = &CPUS[index as usize];
+    // first thing, IrqMutex tells CPUs apart by it and `init_ap` already allocates
+    GsBase::write(VirtAddr::from_ptr(cpu));
+
+    gdt::init_ap(
+        VirtAddr::new(cpu.double_fault_stack.load(Ordering::Relaxed)),
+        VirtAddr::new(cpu.nmi_stack.load(Ordering::Relaxed)),
+        VirtAddr::new(cpu.privilege_stack.load(Ordering::Relaxed)),
+    );
+    interrupts::init_idt();
+    fpu::init();
+    apic::enable();
+    cpu.set_state(CpuState::Online);
+
+    x86_64::instructions::interrupts::enable();
+    hlt_loop();
+}
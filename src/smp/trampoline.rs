@@ -0,0 +1,173 @@
+// The code an application processor starts in
+//
+// A STARTUP IPI starts the AP in 16 bit real mode at a page below 1 MiB, we use 0x8000. The code
+// below is assembled as part of the kernel and copied there, so every address it uses is computed
+// relative to AP_BASE instead of where the linker put it. On the way to long mode it switches to
+// its own small GDT:
+//
+// Selector  Segment
+// 0x08      32 bit code
+// 0x10      32 bit data
+// 0x18      64 bit code
+//
+// The BSP fills in TrampolineData before each start: the page table, the control registers and
+// EFER to copy, and where to continue. The AP reads all of it before jumping to `entry`, so the
+// next AP can be started as soon as this one reports in. Until `entry` loads the kernel's GDT the
+// AP runs on the identity mapped trampoline page.
+
+use core::{arch::global_asm, ptr, slice};
+use x86_64::{
+    registers::{
+        control::{Cr0, Cr3, Cr4},
+        model_specific::{Efer, EferFlags},
+    },
+    structures::paging::{mapper::{MapToError, UnmapError}, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB},
+    PhysAddr,
+    VirtAddr,
+};
+use crate::memory::memory::{phys_to_virt, with_mapper};
+
+// Must match AP_BASE below
+pub const ADDRESS: u64 = 0x8000;
+// What the STARTUP IPI takes
+pub const PAGE: u8 = (ADDRESS / 4096) as u8;
+
+#[repr(C)]
+struct TrampolineData {
+    cr3: u64,
+    cr0: u64,
+    cr4: u64,
+    efer: u64,
+    stack: u64,
+    entry: u64,
+    argument: u64,
+}
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_data: u8;
+    static ap_trampoline_end: u8;
+}
+
+global_asm!(r#"
+.set AP_BASE, 0x8000
+.set AP_DATA, AP_BASE + ap_trampoline_data - ap_trampoline_start
+
+.pushsection .rodata.ap_trampoline, "a"
+.global ap_trampoline_start
+.global ap_trampoline_data
+.global ap_trampoline_end
+
+.code16
+ap_trampoline_start:
+    cli
+    cld
+    xor %ax, %ax
+    mov %ax, %ds
+    lgdtl (AP_BASE + ap_gdt_pointer - ap_trampoline_start)
+    mov %cr0, %eax
+    or $1, %eax
+    mov %eax, %cr0
+    ljmpl $0x08, $(AP_BASE + ap_protected_mode - ap_trampoline_start)
+
+.code32
+ap_protected_mode:
+    mov $0x10, %ax
+    mov %ax, %ds
+    mov %ax, %es
+    mov %ax, %ss
+    mov %cr4, %eax
+    or $(1 << 5), %eax
+    mov %eax, %cr4
+    mov (AP_DATA), %eax
+    mov %eax, %cr3
+    mov $0xC0000080, %ecx
+    mov (AP_DATA + 24), %eax
+    xor %edx, %edx
+    wrmsr
+    mov (AP_DATA + 8), %eax
+    mov %eax, %cr0
+    ljmpl $0x18, $(AP_BASE + ap_long_mode - ap_trampoline_start)
+
+.code64
+ap_long_mode:
+    mov (AP_DATA + 16), %rax
+    mov %rax, %cr4
+    mov (AP_DATA + 32), %rsp
+    mov (AP_DATA + 48), %rdi
+    mov (AP_DATA + 40), %rax
+    call *%rax
+    ud2
+
+.balign 8
+ap_gdt:
+    .quad 0
+    .quad 0x00CF9A000000FFFF
+    .quad 0x00CF92000000FFFF
+    .quad 0x00AF9A000000FFFF
+ap_gdt_pointer:
+    .word ap_gdt_pointer - ap_gdt - 1
+    .long AP_BASE + ap_gdt - ap_trampoline_start
+
+.balign 8
+ap_trampoline_data:
+    .space 56
+ap_trampoline_end:
+.popsection
+"#, options(att_syntax));
+
+fn frame() -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(ADDRESS))
+}
+
+// Copies the trampoline to ADDRESS and identity maps it, it keeps running there after paging is
+// enabled. The page table has to be reachable with a 32 bit CR3.
+pub fn install() -> Result<(), MapToError<Size4KiB>> {
+    unsafe {
+        let start = &ap_trampoline_start as *const u8;
+        let length = &ap_trampoline_end as *const u8 as usize - start as usize;
+        let code = slice::from_raw_parts(start, length);
+        ptr::copy_nonoverlapping(code.as_ptr(), phys_to_virt(frame().start_address()).as_mut_ptr(), code.len());
+    }
+
+    with_mapper(|mapper, frame_allocator| {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        match unsafe { mapper.identity_map(frame(), flags, frame_allocator) } {
+            Ok(flush) => flush.flush(),
+            Err(MapToError::PageAlreadyMapped(mapped)) if mapped == frame() => {}
+            Err(error) => return Err(error),
+        }
+        Ok(())
+    }).unwrap_or(Err(MapToError::FrameAllocationFailed))
+}
+
+pub fn remove() -> Result<(), UnmapError> {
+    with_mapper(|mapper, _| {
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(ADDRESS));
+        mapper.unmap(page).map(|(_, flush)| flush.flush())
+    }).unwrap_or(Err(UnmapError::PageNotMapped))
+}
+
+// Whether CR3 fits the 32 bit register the trampoline loads it from
+pub fn page_table_reachable() -> bool {
+    Cr3::read().0.start_address().as_u64() < 1 << 32
+}
+
+// Makes the next AP continue at `entry(argument)` on `stack`
+pub fn prepare(stack: VirtAddr, entry: extern "C" fn(u64) -> !, argument: u64) {
+    let data = TrampolineData {
+        cr3: Cr3::read().0.start_address().as_u64(),
+        cr0: Cr0::read_raw(),
+        cr4: Cr4::read_raw(),
+        // the active bit can't be written, the CPU sets it itself
+        efer: (Efer::read() - EferFlags::LONG_MODE_ACTIVE).bits(),
+        stack: stack.as_u64(),
+        entry: entry as usize as u64,
+        argument,
+    };
+    unsafe {
+        let offset = &ap_trampoline_data as *const u8 as u64 - &ap_trampoline_start as *const u8 as u64;
+        let address = phys_to_virt(PhysAddr::new(ADDRESS + offset));
+        ptr::write_volatile(address.as_mut_ptr::<TrampolineData>(), data);
+    }
+}
@@ -0,0 +1,265 @@
+// Lockup detector
+//
+// Code that spins with interrupts disabled, e.g. on WRITER or SERIAL1 while the code it
+// interrupted holds them, freezes the BSP without a word. The timer tick stops advancing then,
+// and that's what the watchdog looks for. It can't run on the stuck CPU itself, so it runs on the
+// first AP: its local APIC timer raises VECTOR every PERIOD, and once the BSP's tick hasn't moved
+// for the threshold given to `init` it sends the BSP a non-maskable interrupt (NMI). An NMI gets
+// through with interrupts disabled, its handler prints where the BSP is stuck:
+//
+// WATCHDOG: CPU 0 got no timer tick for 10000 ms
+// RIP 0x20a3f1  CS 0x8  RFLAGS 0x2  RSP 0x...  SS 0x0
+// RAX ...  RBX ...  (all general purpose registers)
+// locks held:
+//   WRITER by CPU 0 at src/shell.rs:52:5
+//
+// The report is printed once per stall and the BSP goes back to what it was doing, once the tick
+// moves again the watchdog is armed again. The NMI entry saves every general purpose register for
+// the report and runs on its own IST stack, see gdt. The handler doesn't wait for any lock: it
+// looks which of the usual suspects are held, and by whom, and prints through writers that don't
+// take WRITER or SERIAL1. It never releases them, the code it interrupted still holds their guards
+// and carries on with them after the report.
+//
+// Without an AP there's nobody to watch the BSP and `init` fails.
+
+use core::{
+    arch::global_asm,
+    fmt::{self, Write},
+    panic::Location,
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+    time::Duration,
+};
+use uart_16550::SerialPort;
+use x86_64::{
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame},
+    VirtAddr,
+};
+use crate::{
+    io::{serial::{self, SERIAL1}, vga_buffer::{self, writer::Writer, WRITER}},
+    memory::{gdt, interrupts::PICS},
+    time,
+};
+use super::{apic, CpuState};
+
+pub const VECTOR: u8 = 0xF0;
+// What the kernel starts the watchdog with
+pub const DEFAULT_THRESHOLD: Duration = Duration::from_secs(10);
+const PERIOD: Duration = Duration::from_millis(100);
+
+// Locks a stuck CPU usually waits for, their holders go into the report
+const LOCKS: [(&str, fn() -> Option<(usize, &'static Location<'static>)>); 3] = [
+    ("WRITER", || WRITER.owner()),
+    ("SERIAL1", || SERIAL1.owner()),
+    ("PICS", || PICS.owner()),
+];
+
+#[derive(Debug)]
+pub enum WatchdogError {
+    // only one CPU is online
+    NoWatcher,
+    AlreadyRunning,
+}
+
+static STARTED: AtomicBool = AtomicBool::new(false);
+// Set once the local APIC timer of the watching AP runs
+static TIMER_RUNNING: AtomicBool = AtomicBool::new(false);
+static THRESHOLD_MILLIS: AtomicU64 = AtomicU64::new(0);
+static TARGET_APIC_ID: AtomicU32 = AtomicU32::new(0);
+static LAST_TICKS: AtomicU64 = AtomicU64::new(0);
+static STALLED_MILLIS: AtomicU64 = AtomicU64::new(0);
+// Whether the current stall was reported, and whether the NMI for it is still on its way
+static REPORTED: AtomicBool = AtomicBool::new(false);
+static NMI_PENDING: AtomicBool = AtomicBool::new(false);
+static REPORTS: AtomicU64 = AtomicU64::new(0);
+// Set while the NMI handler runs, it must stay FPU-free like the IRQ handlers
+static IN_NMI: AtomicBool = AtomicBool::new(false);
+
+// What the NMI entry pushes, lowest address first
+#[derive(Debug)]
+#[repr(C)]
+struct NmiFrame {
+    r15: u64,
+    r14: u64,
+    r13: u64,
+    r12: u64,
+    r11: u64,
+    r10: u64,
+    r9: u64,
+    r8: u64,
+    rbp: u64,
+    rdi: u64,
+    rsi: u64,
+    rdx: u64,
+    rcx: u64,
+    rbx: u64,
+    rax: u64,
+    // pushed by the CPU
+    rip: u64,
+    cs: u64,
+    rflags: u64,
+    rsp: u64,
+    ss: u64,
+}
+
+extern "C" {
+    fn watchdog_nmi_entry();
+}
+
+// The IST stack is 16 byte aligned before the CPU pushes its 5 values, with the 15 registers on
+// top it's aligned again for the call
+global_asm!(r#"
+.global watchdog_nmi_entry
+watchdog_nmi_entry:
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+    mov rdi, rsp
+    cld
+    call watchdog_nmi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+    iretq
+"#);
+
+// Starts watching the BSP from the first online AP. Must be called after `smp::init`.
+pub fn init(threshold: Duration) -> Result<(), WatchdogError> {
+    let watcher = super::cpus()
+        .find(|cpu| !cpu.is_bootstrap() && cpu.state() == CpuState::Online)
+        .ok_or(WatchdogError::NoWatcher)?;
+    if STARTED.swap(true, Ordering::SeqCst) {
+        return Err(WatchdogError::AlreadyRunning);
+    }
+
+    THRESHOLD_MILLIS.store(threshold.as_millis() as u64, Ordering::Relaxed);
+    TARGET_APIC_ID.store(apic::id(), Ordering::Relaxed);
+    LAST_TICKS.store(time::ticks(), Ordering::Relaxed);
+    // the first interrupt sets up the timer on the AP, see `tick_handler`
+    apic::send_fixed(watcher.apic_id(), VECTOR);
+    Ok(())
+}
+
+pub fn is_running() -> bool {
+    TIMER_RUNNING.load(Ordering::Relaxed)
+}
+
+pub fn threshold() -> Duration {
+    Duration::from_millis(THRESHOLD_MILLIS.load(Ordering::Relaxed))
+}
+
+// Whether we are inside the NMI handler
+pub fn in_nmi() -> bool {
+    IN_NMI.load(Ordering::SeqCst)
+}
+
+// Number of stalls reported since boot
+pub fn reports() -> u64 {
+    REPORTS.load(Ordering::Relaxed)
+}
+
+// Runs on the watching AP
+extern "x86-interrupt" fn tick_handler(_stack_frame: InterruptStackFrame) {
+    if TIMER_RUNNING.load(Ordering::Relaxed) {
+        check();
+    } else {
+        // the IPI from `init`, nothing else runs on this CPU so the 10 ms don't hurt
+        let count = apic::calibrate_timer() * PERIOD.as_millis() as u64 / 1000;
+        apic::start_periodic_timer(VECTOR, count as u32);
+        TIMER_RUNNING.store(true, Ordering::Relaxed);
+    }
+    apic::end_of_interrupt();
+}
+
+fn check() {
+    let ticks = time::ticks();
+    if ticks != LAST_TICKS.swap(ticks, Ordering::Relaxed) {
+        STALLED_MILLIS.store(0, Ordering::Relaxed);
+        REPORTED.store(false, Ordering::Relaxed);
+        return;
+    }
+
+    let stalled = STALLED_MILLIS.fetch_add(PERIOD.as_millis() as u64, Ordering::Relaxed) + PERIOD.as_millis() as u64;
+    if stalled >= THRESHOLD_MILLIS.load(Ordering::Relaxed) && !REPORTED.swap(true, Ordering::Relaxed) {
+        NMI_PENDING.store(true, Ordering::SeqCst);
+        apic::send_nmi(TARGET_APIC_ID.load(Ordering::Relaxed));
+    }
+}
+
+// Both the screen and serial, without taking their locks
+struct Report {
+    screen: Writer,
+    serial: SerialPort,
+}
+impl Report {
+    fn emit(&mut self, args: fmt::Arguments) {
+        let _ = self.screen.write_fmt(args);
+        let _ = self.serial.write_fmt(args);
+    }
+}
+
+#[no_mangle]
+extern "C" fn watchdog_nmi(frame: &NmiFrame) {
+    IN_NMI.store(true, Ordering::SeqCst);
+    let cpu = super::current().index();
+    let locks = LOCKS.map(|(name, owner)| (name, owner()));
+    let mut report = Report { screen: vga_buffer::emergency_writer(), serial: serial::emergency_port() };
+
+    if NMI_PENDING.swap(false, Ordering::SeqCst) {
+        REPORTS.fetch_add(1, Ordering::Relaxed);
+        report.emit(format_args!("\nWATCHDOG: CPU {} got no timer tick for {} ms\n", cpu, STALLED_MILLIS.load(Ordering::Relaxed)));
+    } else {
+        // not ours, e.g. `nmi` in the QEMU monitor
+        report.emit(format_args!("\nNMI on CPU {}\n", cpu));
+    }
+    report.emit(format_args!(
+        "RIP {:#x}  CS {:#x}  RFLAGS {:#x}  RSP {:#x}  SS {:#x}\n",
+        frame.rip, frame.cs, frame.rflags, frame.rsp, frame.ss
+    ));
+    report.emit(format_args!(
+        "RAX {:#x}  RBX {:#x}  RCX {:#x}  RDX {:#x}  RSI {:#x}  RDI {:#x}  RBP {:#x}\n",
+        frame.rax, frame.rbx, frame.rcx, frame.rdx, frame.rsi, frame.rdi, frame.rbp
+    ));
+    report.emit(format_args!(
+        "R8 {:#x}  R9 {:#x}  R10 {:#x}  R11 {:#x}  R12 {:#x}  R13 {:#x}  R14 {:#x}  R15 {:#x}\n",
+        frame.r8, frame.r9, frame.r10, frame.r11, frame.r12, frame.r13, frame.r14, frame.r15
+    ));
+    report.emit(format_args!("locks held:"));
+    for (name, (cpu, location)) in locks.iter().filter_map(|(name, owner)| Some((name, (*owner)?))) {
+        report.emit(format_args!("\n  {} by CPU {} at {}", name, cpu, location));
+    }
+    report.emit(format_args!("{}\n", if locks.iter().any(|(_, owner)| owner.is_some()) { "" } else { " none" }));
+    IN_NMI.store(false, Ordering::SeqCst);
+}
+
+pub(crate) fn set_idt_entries(idt: &mut InterruptDescriptorTable) {
+    idt[VECTOR as usize].set_handler_fn(tick_handler);
+    unsafe {
+        idt.non_maskable_interrupt
+            .set_handler_addr(VirtAddr::new(watchdog_nmi_entry as unsafe extern "C" fn() as u64))
+            .set_stack_index(gdt::NMI_IST_INDEX);
+    }
+}
@@ -0,0 +1,166 @@
+// Locks for kernel globals
+//
+// A plain spinlock isn't enough in a kernel: if an interrupt handler takes a lock the code it
+// interrupted holds, it spins forever, the holder can't run until the handler returns. IrqMutex
+// disables interrupts for as long as its guard lives and restores them afterwards, so that can't
+// happen and `without_interrupts` around `lock` isn't needed anymore.
+//
+// Every IrqMutex also records who holds it: the index of the CPU and the caller of `lock`. With
+// interrupts off, a CPU trying to take a lock it already holds waits for itself, instead of
+// spinning forever `lock` panics with both locations:
+//
+// deadlock: src/io/vga_buffer.rs:31:22 on CPU 0 tried to lock a mutex it already holds since
+// src/shell.rs:140:5
+//
+// The watchdog (smp::watchdog) reports the holders of the global locks the same way.
+//
+// Guards of nested locks have to be dropped in reverse order, the outermost one turns interrupts
+// back on.
+
+use core::{
+    cell::UnsafeCell,
+    hint::spin_loop,
+    ops::{Deref, DerefMut},
+    panic::Location,
+    ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering},
+};
+use x86_64::instructions::interrupts;
+use crate::smp;
+
+// Owner CPU of a lock nobody holds
+const NO_CPU: usize = usize::MAX;
+
+pub struct IrqMutex<T> {
+    locked: AtomicBool,
+    owner_cpu: AtomicUsize,
+    owner_location: AtomicPtr<Location<'static>>,
+    data: UnsafeCell<T>,
+}
+unsafe impl<T: Send> Sync for IrqMutex<T> {}
+unsafe impl<T: Send> Send for IrqMutex<T> {}
+
+impl<T> IrqMutex<T> {
+    pub const fn new(data: T) -> Self {
+        IrqMutex {
+            locked: AtomicBool::new(false),
+            owner_cpu: AtomicUsize::new(NO_CPU),
+            owner_location: AtomicPtr::new(ptr::null_mut()),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    // Spins until the lock is free, with interrupts disabled until the guard is dropped. Panics
+    // if this CPU already holds it.
+    #[track_caller]
+    pub fn lock(&self) -> IrqMutexGuard<'_, T> {
+        let caller = Location::caller();
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        let cpu = smp::current().index();
+
+        while self.locked.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            if self.owner_cpu.load(Ordering::Relaxed) == cpu {
+                let (_, owner) = self.owner().unwrap_or((cpu, caller));
+                // the panic handler prints through WRITER, which might be this very lock
+                unsafe { self.force_unlock() };
+                panic!("deadlock: {} on CPU {} tried to lock a mutex it already holds since {}", caller, cpu, owner);
+            }
+            spin_loop();
+        }
+        self.acquired(cpu, caller, interrupts_enabled)
+    }
+
+    // Like `lock`, but returns None instead of waiting
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<IrqMutexGuard<'_, T>> {
+        let caller = Location::caller();
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+
+        if self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+            Some(self.acquired(smp::current().index(), caller, interrupts_enabled))
+        } else {
+            if interrupts_enabled {
+                interrupts::enable();
+            }
+            None
+        }
+    }
+
+    fn acquired(&self, cpu: usize, caller: &'static Location<'static>, interrupts_enabled: bool) -> IrqMutexGuard<'_, T> {
+        self.owner_location.store(caller as *const _ as *mut _, Ordering::Relaxed);
+        self.owner_cpu.store(cpu, Ordering::Relaxed);
+        IrqMutexGuard { mutex: self, interrupts_enabled }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    // CPU index and caller of `lock` of the current holder. Only a hint while other CPUs run, the
+    // lock can change hands while this reads it.
+    pub fn owner(&self) -> Option<(usize, &'static Location<'static>)> {
+        let cpu = self.owner_cpu.load(Ordering::Relaxed);
+        let location = self.owner_location.load(Ordering::Relaxed);
+        if cpu == NO_CPU || location.is_null() {
+            None
+        } else {
+            Some((cpu, unsafe { &*location }))
+        }
+    }
+
+    // Releases the lock without a guard, for when the holder is never going to
+    //
+    // Safety: the holder's guard must not be used anymore
+    pub unsafe fn force_unlock(&self) {
+        self.owner_cpu.store(NO_CPU, Ordering::Relaxed);
+        self.owner_location.store(ptr::null_mut(), Ordering::Relaxed);
+        self.locked.store(false, Ordering::Release);
+    }
+}
+
+pub struct IrqMutexGuard<'a, T> {
+    mutex: &'a IrqMutex<T>,
+    // whether interrupts were enabled before `lock`
+    interrupts_enabled: bool,
+}
+impl<T> Deref for IrqMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+impl<T> DerefMut for IrqMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+impl<T> Drop for IrqMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe { self.mutex.force_unlock() };
+        if self.interrupts_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+// Tests
+#[test_case]
+fn test_irq_mutex() {
+    let mutex = IrqMutex::new(1);
+    assert!(interrupts::are_enabled());
+    {
+        let mut guard = mutex.lock();
+        *guard += 1;
+        assert!(!interrupts::are_enabled());
+        assert!(mutex.try_lock().is_none());
+        let (cpu, location) = mutex.owner().unwrap();
+        assert_eq!(cpu, smp::current().index());
+        assert_eq!(location.file(), file!());
+    }
+    assert!(interrupts::are_enabled());
+    assert!(!mutex.is_locked() && mutex.owner().is_none());
+    assert_eq!(*mutex.lock(), 2);
+}
@@ -1,30 +1,43 @@
 use super::{
+    join::{self, JoinHandle},
+    timer,
+    Priority,
     Task,
     TaskId,
 };
+use crate::sync::IrqMutex;
 use alloc::{
-    collections::BTreeMap,
+    collections::{BTreeMap, VecDeque},
     sync::Arc,
     task::Wake,
 };
-use core::task::{
-    Waker,
-    Context,
-    Poll,
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{
+        AtomicU32,
+        Ordering,
+    },
+    task::{
+        Waker,
+        Context,
+        Poll,
+    },
 };
 use crossbeam_queue::ArrayQueue;
+use lazy_static::lazy_static;
 
 // Instead of storing tasks in a VecDeque, we use the task_queue of task IDs and a BTreeMap named
 // tasks that contains the actual Task instances. The map is indexed by the TaskId to allow
 // efficient continuation of a specific task.
 //
-// The task_queue field is an ArrayQueue of task IDs, wrapped into the Arc type that implements
-// reference counting. Reference counting makes it possible to share ownership of the value among
+// The task_queue field holds an ArrayQueue of task IDs per priority, wrapped into the Arc type
+// that implements reference counting. Reference counting makes it possible to share ownership of the value among
 // multiple owners. It works by allocating the value on the heap and counting the number of active
 // references to it. When the number of active references reaches zero, the value is no longer
 // needed and can be deallocated.
 //
-// We use this Arc<ArrayQueue> type for the task_queue because it will be shared between the
+// We use this Arc<TaskQueue> type for the task_queue because it will be shared between the
 // executor and wakers. The idea is that the wakers push the ID of the woken task to the queue. The
 // executor sites on the receiving end of the queue, retrieves the woken tasks by their ID from the
 // tasks map, and then runs them. The reason for using a fixed-size queue instead of an unbounded
@@ -36,10 +49,126 @@ use crossbeam_queue::ArrayQueue;
 //    instead of creating a new waker each time.
 //  * it ensures that reference-counted wakers are not deallocated inside interrupt hanlders
 //    because it could head to deadlocks
+//
+// Every poll takes the next task of the highest priority that has one ready, so an input task
+// doesn't wait behind a busy background one. A busy task still has to give the others a chance:
+// each poll comes with a budget of POLL_BUDGET units, `cooperate` spends one and yields once it's
+// used up, `yield_now` yields right away. To keep a stream of higher priority work from starving
+// the lower levels, a level that was passed over AGING_LIMIT times in a row gets the next poll.
+//
+// `spawn` needs &mut self, which a running task doesn't have. Tasks started from inside other
+// tasks, interrupt handlers or other CPUs go through a Spawner instead: it pushes them into the
+// inbox, and the executor moves them over to its tasks map before it looks for ready tasks.
 pub struct Executor {
     tasks: BTreeMap<TaskId, Task>,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    task_queue: Arc<TaskQueue>,
     waker_cache: BTreeMap<TaskId, Waker>,
+    inbox: Arc<Inbox>,
+    // polls in a row each level had a ready task but didn't get one
+    skipped: [u32; Priority::ALL.len()],
+}
+
+// Units of work a task may do per poll before `cooperate` yields
+pub const POLL_BUDGET: u32 = 64;
+// Polls a lower priority level is passed over before it gets one anyway
+pub const AGING_LIMIT: u32 = 8;
+
+// Budget left for the task being polled
+static BUDGET: AtomicU32 = AtomicU32::new(POLL_BUDGET);
+
+// The ready queues, one per priority
+struct TaskQueue {
+    levels: [ArrayQueue<TaskId>; Priority::ALL.len()],
+}
+impl TaskQueue {
+    fn new(capacity: usize) -> Arc<TaskQueue> {
+        Arc::new(TaskQueue {
+            levels: [ArrayQueue::new(capacity), ArrayQueue::new(capacity), ArrayQueue::new(capacity)],
+        })
+    }
+
+    fn push(&self, priority: Priority, task_id: TaskId) {
+        self.levels[priority as usize].push(task_id).expect("task_queue full");
+    }
+
+    fn pop(&self, priority: Priority) -> Option<TaskId> {
+        self.levels[priority as usize].pop().ok()
+    }
+
+    fn has_ready(&self, priority: Priority) -> bool {
+        !self.levels[priority as usize].is_empty()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.levels.iter().all(|level| level.is_empty())
+    }
+}
+
+// Tasks spawned but not yet picked up by an executor. IrqMutex makes it safe to push from
+// interrupt handlers, as long as they don't mind allocating the task.
+struct Inbox {
+    tasks: IrqMutex<VecDeque<Task>>,
+}
+impl Inbox {
+    fn new() -> Arc<Inbox> {
+        Arc::new(Inbox { tasks: IrqMutex::new(VecDeque::new()) })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.tasks.lock().is_empty()
+    }
+}
+
+lazy_static! {
+    // Inbox of the global `spawn`, drained by the executor that `run`s
+    static ref GLOBAL_INBOX: Arc<Inbox> = Inbox::new();
+}
+
+// Handle for spawning tasks on an executor without access to it
+#[derive(Clone)]
+pub struct Spawner {
+    inbox: Arc<Inbox>,
+}
+impl Spawner {
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let (task, handle) = join::joinable(future);
+        self.spawn_task(task);
+        handle
+    }
+
+    pub fn spawn_with_priority<F>(&self, future: F, priority: Priority) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let (task, handle) = join::joinable(future);
+        self.spawn_task(task.with_priority(priority));
+        handle
+    }
+
+    pub fn spawn_task(&self, task: Task) {
+        self.inbox.tasks.lock().push_back(task);
+    }
+}
+
+// Spawner of the executor that runs the kernel. Tasks spawned before it runs wait in the inbox.
+pub fn spawner() -> Spawner {
+    Spawner { inbox: GLOBAL_INBOX.clone() }
+}
+
+// Spawns `future` on the executor that runs the kernel. The task is picked up the next time the
+// executor wakes up: right away from inside a task or an interrupt handler, but from another CPU
+// only on the next interrupt of the BSP.
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    spawner().spawn(future)
 }
 impl Executor {
     // To create an Executor, we provide a simple new function. We choose a capacity of 100 for the
@@ -48,63 +177,117 @@ impl Executor {
     pub fn new() -> Self {
         Executor {
             tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(100)),
+            task_queue: TaskQueue::new(100),
             waker_cache: BTreeMap::new(),
+            inbox: Inbox::new(),
+            skipped: [0; Priority::ALL.len()],
         }
     }
 
+    // Handle for spawning tasks on this executor
+    pub fn spawner(&self) -> Spawner {
+        Spawner { inbox: self.inbox.clone() }
+    }
+
     // If there is already a task with the same ID in the map, the [BTreeMap::insert] method
     // returns it. This should never happen since each task has a unique ID, so we panic in this
     // case since it indicates a bug iin our code. Similarly, we panic when the task_queue is full
     // since this should nevner happen fi we choose a large enough queue size.
     pub fn spawn(&mut self, task: Task) {
-        let task_id = task.id;
+        let (task_id, priority) = (task.id, task.priority);
         if self.tasks.insert(task.id, task) .is_some() {
             panic!("task with same ID already in tasks");
         }
-        self.task_queue.push(task_id).expect("queue full");
+        self.task_queue.push(priority, task_id);
     }
 
+    // Runs the tasks forever, together with the ones from the global `spawn`
     pub fn run(&mut self) -> ! {
         loop {
-            self.run_ready_task();
-            self.sleep_if_idle();
+            self.take_spawned(&GLOBAL_INBOX);
+            if !self.run_once() {
+                self.sleep_if_idle();
+            }
+        }
+    }
+
+    // Runs this executor's tasks until none of them is ready anymore, without sleeping and without
+    // the global `spawn`. For tests and for code that drives an executor of its own. Doesn't
+    // return while a task keeps yielding.
+    pub fn run_until_idle(&mut self) {
+        while self.run_once() {}
+    }
+
+    // Moves the tasks waiting in `inbox` over, without holding its lock while spawning
+    fn take_spawned(&mut self, inbox: &Inbox) {
+        let tasks = core::mem::take(&mut *inbox.tasks.lock());
+        for task in tasks {
+            self.spawn(task);
         }
     }
 
-    // The basic idea of this function is to loop over all tasks in the task_queue, create a waker
-    // for each task, and then poll them. However, instead of adding pending tasks back to the end
-    // of the task_queue, we let our TaskWaker implementation take care of adding woken tasks back
-    // to the queue.
-    fn run_ready_task(&mut self) {
+    // Polls the next ready task once, returns false if there was none. The basic idea is to take
+    // a task from the task_queue, create a waker for it, and then poll it. However, instead of
+    // adding a pending task back to the end of the task_queue, we let our TaskWaker implementation
+    // take care of adding woken tasks back to the queue.
+    pub fn run_once(&mut self) -> bool {
+        timer::wake_expired();
+        let inbox = self.inbox.clone();
+        self.take_spawned(&inbox);
+        let (priority, task_id) = match self.next_ready() {
+            Some(next) => next,
+            None => return false,
+        };
+
         // destruct `self` to avoid borrow checker errors
         let Self {
             tasks,
             task_queue,
             waker_cache,
+            ..
         } = self;
 
-        while let Ok(task_id) = task_queue.pop() {
-            let task = match tasks.get_mut(&task_id) {
-                Some(task) => task,
-                None => continue, // task no longer exists
-            };
-            let waker = waker_cache.entry(task_id).or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
-            let mut context = Context::from_waker(waker);
-            match task.poll(&mut context) {
-                Poll::Ready(()) => {
-                    // task done -> remvoe it and its cached waker
-                    tasks.remove(&task_id);
-                    waker_cache.remove(&task_id);
-                }
-                Poll::Pending => {}
+        let task = match tasks.get_mut(&task_id) {
+            Some(task) => task,
+            None => return true, // task no longer exists
+        };
+        let waker = waker_cache.entry(task_id).or_insert_with(|| TaskWaker::new(task_id, priority, task_queue.clone()));
+        let mut context = Context::from_waker(waker);
+        BUDGET.store(POLL_BUDGET, Ordering::Relaxed);
+        match task.poll(&mut context) {
+            Poll::Ready(()) => {
+                // task done -> remvoe it and its cached waker
+                tasks.remove(&task_id);
+                waker_cache.remove(&task_id);
             }
+            Poll::Pending => {}
         }
+        true
+    }
+
+    // Takes the next task from the highest priority level with one ready, unless a lower level
+    // waited for AGING_LIMIT polls already. Lower levels age first, so background tasks get their
+    // turn even while interactive ones wait too.
+    fn next_ready(&mut self) -> Option<(Priority, TaskId)> {
+        let aged = Priority::ALL.iter().rev().copied().find(|priority| {
+            self.skipped[*priority as usize] >= AGING_LIMIT && self.task_queue.has_ready(*priority)
+        });
+        let priority = aged.or_else(|| Priority::ALL.iter().copied().find(|priority| self.task_queue.has_ready(*priority)))?;
+        let task_id = self.task_queue.pop(priority)?;
+
+        for level in Priority::ALL {
+            if level == priority {
+                self.skipped[level as usize] = 0;
+            } else if level > priority && self.task_queue.has_ready(level) {
+                self.skipped[level as usize] += 1;
+            }
+        }
+        Some((priority, task_id))
     }
 
     // We have to disable the interrupts before the if statment because interrupts can happen at
     // any time and that might be just after the if statment is passed and we halt even when we just got
-    // an interrupt.
+    // an interrupt. Expired timers count as work too, their tasks are woken on the next iteration.
     fn sleep_if_idle(&self) {
         use x86_64::instructions::interrupts::{
             self,
@@ -113,7 +296,8 @@ impl Executor {
 
         interrupts::disable();
 
-        if self.task_queue.is_empty() {
+        let idle = self.task_queue.is_empty() && self.inbox.is_empty() && GLOBAL_INBOX.is_empty();
+        if idle && !timer::has_expired() {
             enable_and_hlt();
         } else {
             interrupts::enable();
@@ -123,20 +307,22 @@ impl Executor {
 
 struct TaskWaker {
     task_id: TaskId,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    priority: Priority,
+    task_queue: Arc<TaskQueue>,
 }
 impl TaskWaker {
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
+    fn new(task_id: TaskId, priority: Priority, task_queue: Arc<TaskQueue>) -> Waker {
         Waker::from(Arc::new(TaskWaker {
             task_id,
+            priority,
             task_queue,
         }))
     }
 
-    // We push the task_id to the referenced task_queue. Since modifications to the ArrayQueue type
+    // We push the task_id to the queue of its priority. Since modifications to the ArrayQueue type
     // only require a shared reference, we can implement this method on &self instead of &mut self.
     fn wake_task(&self) {
-        self.task_queue.push(self.task_id).expect("task_queue full");
+        self.task_queue.push(self.priority, self.task_id);
     }
 }
 // In order to use our TaskWaker type for polling futures, we need to convert it to a Waker
@@ -153,3 +339,173 @@ impl Wake for TaskWaker {
         self.wake_task();
     }
 }
+
+// Future that is pending once, the task goes to the back of the queue of its priority and the
+// other ready tasks run first
+pub struct YieldNow {
+    yielded: bool,
+}
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        self.yielded = true;
+        context.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+// Spends one unit of the poll budget and yields once it's used up, for busy loops that would
+// yield far too often with `yield_now` on every iteration
+pub async fn cooperate() {
+    let left = BUDGET.load(Ordering::Relaxed);
+    if left == 0 {
+        yield_now().await;
+    } else {
+        BUDGET.store(left - 1, Ordering::Relaxed);
+    }
+}
+
+// Tests
+#[test_case]
+fn test_join_output() {
+    use core::sync::atomic::{AtomicU64, Ordering};
+    use futures_util::task::noop_waker;
+
+    static JOINED: AtomicU64 = AtomicU64::new(0);
+
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    let mut answer = spawner.spawn(async { 6 * 7 });
+    let inner = spawner.clone();
+    spawner.spawn(async move {
+        let answer = inner.spawn(async { 40 + 2 }).await.unwrap();
+        JOINED.store(answer, Ordering::SeqCst);
+    }).detach();
+    executor.run_until_idle();
+
+    assert!(answer.is_finished());
+    let waker = noop_waker();
+    let mut context = Context::from_waker(&waker);
+    assert_eq!(Pin::new(&mut answer).poll(&mut context), Poll::Ready(Ok(42)));
+    assert_eq!(JOINED.load(Ordering::SeqCst), 42);
+    assert!(executor.tasks.is_empty());
+}
+
+#[test_case]
+fn test_abort() {
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use futures_util::{future::pending, task::noop_waker};
+
+    static DROPPED: AtomicBool = AtomicBool::new(false);
+    struct DropFlag;
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            DROPPED.store(true, Ordering::SeqCst);
+        }
+    }
+
+    let mut executor = Executor::new();
+    let flag = DropFlag;
+    let mut handle = executor.spawner().spawn(async move {
+        let _flag = flag;
+        pending::<()>().await;
+    });
+    executor.run_until_idle();
+    assert!(!handle.is_finished() && !DROPPED.load(Ordering::SeqCst));
+
+    handle.abort();
+    executor.run_until_idle();
+    assert!(DROPPED.load(Ordering::SeqCst));
+    assert!(executor.tasks.is_empty() && executor.waker_cache.is_empty());
+    let waker = noop_waker();
+    let mut context = Context::from_waker(&waker);
+    assert_eq!(Pin::new(&mut handle).poll(&mut context), Poll::Ready(Err(join::Aborted)));
+}
+
+#[test_case]
+fn test_input_stays_responsive() {
+    use core::sync::atomic::AtomicU64;
+    use futures_util::stream::StreamExt;
+    use pc_keyboard::{DecodedKey, KeyCode, KeyState};
+    use super::keyboard::{self, KeyEvent, KeyEventStream, Modifiers};
+
+    static BUSY: AtomicU64 = AtomicU64::new(0);
+    static RECEIVED: AtomicU64 = AtomicU64::new(0);
+
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    // always ready, the executor never runs out of work
+    let busy = spawner.spawn_with_priority(async {
+        loop {
+            BUSY.fetch_add(1, Ordering::SeqCst);
+            cooperate().await;
+        }
+    }, Priority::Background);
+    let mut events = KeyEventStream::subscribe().expect("no free keyboard subscriber slot");
+    spawner.spawn_with_priority(async move {
+        while RECEIVED.load(Ordering::SeqCst) < 3 {
+            events.next().await;
+            RECEIVED.fetch_add(1, Ordering::SeqCst);
+        }
+    }, Priority::Input).detach();
+
+    let event = KeyEvent {
+        code: KeyCode::A,
+        state: KeyState::Down,
+        modifiers: Modifiers::default(),
+        key: Some(DecodedKey::Unicode('a')),
+    };
+    for received in 1..=3 {
+        keyboard::publish(event);
+        // the very next poll goes to the input task, however long the busy one has been running
+        assert!(executor.run_once());
+        assert_eq!(RECEIVED.load(Ordering::SeqCst), received);
+        for _ in 0..2 * AGING_LIMIT {
+            executor.run_once();
+        }
+    }
+    // the budget made it yield after POLL_BUDGET iterations, every time
+    assert!(BUSY.load(Ordering::SeqCst) > 3 * POLL_BUDGET as u64);
+
+    busy.abort();
+    executor.run_until_idle();
+    assert!(executor.tasks.is_empty());
+}
+
+#[test_case]
+fn test_aging() {
+    use core::sync::atomic::AtomicBool;
+
+    static BACKGROUND_RAN: AtomicBool = AtomicBool::new(false);
+
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    let busy = spawner.spawn(async {
+        loop {
+            yield_now().await;
+        }
+    });
+    spawner.spawn_with_priority(async {
+        BACKGROUND_RAN.store(true, Ordering::SeqCst);
+    }, Priority::Background).detach();
+
+    // passed over AGING_LIMIT times, the next poll is the background task's
+    for _ in 0..AGING_LIMIT {
+        executor.run_once();
+    }
+    assert!(!BACKGROUND_RAN.load(Ordering::SeqCst));
+    executor.run_once();
+    assert!(BACKGROUND_RAN.load(Ordering::SeqCst));
+
+    busy.abort();
+    executor.run_until_idle();
+    assert!(executor.tasks.is_empty());
+}
@@ -0,0 +1,115 @@
+use super::Task;
+use crate::sync::IrqMutex;
+use alloc::sync::Arc;
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{
+        AtomicBool,
+        Ordering,
+    },
+    task::{
+        Context,
+        Poll,
+    },
+};
+use futures_util::task::AtomicWaker;
+
+// A spawned task and its JoinHandle share a JoinState. The task stores its output there and wakes
+// whoever awaits the handle. Aborting sets a flag and wakes the task, the next time the executor
+// polls it the task completes without polling its future again. The executor then removes it from
+// its tasks map and waker cache like any other finished task, which drops the future.
+struct JoinState<T> {
+    output: IrqMutex<Option<T>>,
+    finished: AtomicBool,
+    aborted: AtomicBool,
+    // the task waiting for the output, and the spawned task itself for `abort`
+    join_waker: AtomicWaker,
+    task_waker: AtomicWaker,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Aborted;
+
+// Resolves to the output of a spawned task, or Err(Aborted) if it was aborted before it finished.
+// Dropping the handle detaches the task, it keeps running and its output is dropped.
+pub struct JoinHandle<T> {
+    state: Arc<JoinState<T>>,
+}
+impl<T> JoinHandle<T> {
+    // Stops the task, its future is dropped the next time the executor gets to it. Does nothing
+    // if the task already finished.
+    pub fn abort(&self) {
+        self.state.aborted.store(true, Ordering::Release);
+        self.state.task_waker.wake();
+    }
+
+    // Lets the task run on without anybody waiting for it
+    pub fn detach(self) {}
+
+    pub fn is_finished(&self) -> bool {
+        self.state.finished.load(Ordering::Acquire)
+    }
+}
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, Aborted>;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<Self::Output> {
+        // register before checking, the task might finish in between
+        self.state.join_waker.register(context.waker());
+        if !self.state.finished.load(Ordering::Acquire) {
+            return Poll::Pending;
+        }
+        match self.state.output.lock().take() {
+            Some(output) => Poll::Ready(Ok(output)),
+            None => Poll::Ready(Err(Aborted)),
+        }
+    }
+}
+
+// The future of a task with a JoinHandle
+struct Joinable<F: Future> {
+    future: F,
+    state: Arc<JoinState<F::Output>>,
+}
+impl<F: Future> Future for Joinable<F> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<()> {
+        // `future` is structurally pinned, we never move it out of `self`. `state` is Unpin.
+        let this = unsafe { self.get_unchecked_mut() };
+        let state = &this.state;
+
+        state.task_waker.register(context.waker());
+        let output = if state.aborted.load(Ordering::Acquire) {
+            None
+        } else {
+            match unsafe { Pin::new_unchecked(&mut this.future) }.poll(context) {
+                Poll::Ready(output) => Some(output),
+                Poll::Pending => return Poll::Pending,
+            }
+        };
+
+        *state.output.lock() = output;
+        state.finished.store(true, Ordering::Release);
+        state.join_waker.wake();
+        Poll::Ready(())
+    }
+}
+
+// Wraps `future` into a task whose output goes to the returned handle
+pub fn joinable<F>(future: F) -> (Task, JoinHandle<F::Output>)
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let state = Arc::new(JoinState {
+        output: IrqMutex::new(None),
+        finished: AtomicBool::new(false),
+        aborted: AtomicBool::new(false),
+        join_waker: AtomicWaker::new(),
+        task_waker: AtomicWaker::new(),
+    });
+    let task = Task::new(Joinable { future, state: state.clone() });
+    (task, JoinHandle { state })
+}
@@ -1,11 +1,18 @@
 use crate::{
-    println,
     print,
+    println,
+    io::ps2::{self, Device, Leds},
+    memory::irq::{self, IrqAction, IrqResult},
+    sync::IrqMutex,
 };
 use conquer_once::spin::OnceCell;
 use crossbeam_queue::ArrayQueue;
 use core::{
     pin::Pin,
+    sync::atomic::{
+        AtomicBool,
+        Ordering,
+    },
     task::{
         Poll,
         Context
@@ -18,20 +25,264 @@ use futures_util::{
     },
     task::AtomicWaker,
 };
+use lazy_static::lazy_static;
 use pc_keyboard::{
     layouts::Us104Key,
     DecodedKey,
     HandleControl,
+    KeyCode,
+    KeyState,
     Keyboard,
-    ScancodeSet1
+    ScancodeSet1,
+    ScancodeSet2,
 };
+use super::keymap::{self, ScancodeSet};
+
+// Keyboard input is decoded once, in the interrupt handler, and every decoded KeyEvent is published
+// to all subscribers. Each subscriber has its own queue, so a slow consumer only loses its own
+// events. The subscriber slots and their queues are allocated outside of the interrupt handler,
+// publishing only pushes into the existing queues.
+//
+// The raw scancodes are still available through ScancodeStream, for code that wants to do its own
+// decoding.
+
+// Maximum number of KeyEventStreams alive at the same time
+pub const MAX_SUBSCRIBERS: usize = 8;
+const SUBSCRIBER_QUEUE_SIZE: usize = 100;
 
 static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
 static WAKER: AtomicWaker = AtomicWaker::new();
 
-// Called by the keyboard interrupt hanlder
+struct Subscriber {
+    queue: ArrayQueue<KeyEvent>,
+    waker: AtomicWaker,
+}
+// A slot is in use while `SUBSCRIBED` is set, the queue is kept for the next subscriber
+static SUBSCRIBERS: [OnceCell<Subscriber>; MAX_SUBSCRIBERS] = [const { OnceCell::uninit() }; MAX_SUBSCRIBERS];
+static SUBSCRIBED: [AtomicBool; MAX_SUBSCRIBERS] = [const { AtomicBool::new(false) }; MAX_SUBSCRIBERS];
+
+// State of the modifier keys when the event happened
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Modifiers {
+    pub left_shift: bool,
+    pub right_shift: bool,
+    pub left_ctrl: bool,
+    pub right_ctrl: bool,
+    pub alt: bool,
+    pub alt_gr: bool,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+// NumLock starts out on, like pc-keyboard's decoder and the keyboard's LED
+impl Default for Modifiers {
+    fn default() -> Self {
+        Modifiers {
+            left_shift: false,
+            right_shift: false,
+            left_ctrl: false,
+            right_ctrl: false,
+            alt: false,
+            alt_gr: false,
+            caps_lock: false,
+            num_lock: true,
+            scroll_lock: false,
+        }
+    }
+}
+impl Modifiers {
+    pub fn shift(&self) -> bool {
+        self.left_shift || self.right_shift
+    }
+
+    pub fn ctrl(&self) -> bool {
+        self.left_ctrl || self.right_ctrl
+    }
+
+    // Updates the state for a key press or release. Returns true if `code` is a modifier key.
+    fn update(&mut self, code: KeyCode, state: KeyState) -> bool {
+        let down = state == KeyState::Down;
+        match code {
+            KeyCode::ShiftLeft => self.left_shift = down,
+            KeyCode::ShiftRight => self.right_shift = down,
+            KeyCode::ControlLeft => self.left_ctrl = down,
+            KeyCode::ControlRight => self.right_ctrl = down,
+            KeyCode::AltLeft => self.alt = down,
+            KeyCode::AltRight => self.alt_gr = down,
+            // the lock keys toggle on press
+            KeyCode::CapsLock => self.caps_lock ^= down,
+            KeyCode::NumpadLock => self.num_lock ^= down,
+            KeyCode::ScrollLock => self.scroll_lock ^= down,
+            _ => return false,
+        }
+        true
+    }
+
+    fn leds(&self) -> Leds {
+        Leds {
+            scroll_lock: self.scroll_lock,
+            num_lock: self.num_lock,
+            caps_lock: self.caps_lock,
+        }
+    }
+
+    fn to_pc_keyboard(&self) -> pc_keyboard::Modifiers {
+        pc_keyboard::Modifiers {
+            lshift: self.left_shift,
+            rshift: self.right_shift,
+            lctrl: self.left_ctrl,
+            rctrl: self.right_ctrl,
+            numlock: self.num_lock,
+            capslock: self.caps_lock,
+            alt_gr: self.alt_gr,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub code: KeyCode,
+    pub state: KeyState,
+    pub modifiers: Modifiers,
+    // The key as translated by the layout, only set when a non-modifier key is pressed
+    pub key: Option<DecodedKey>,
+}
+impl KeyEvent {
+    pub fn is_press(&self) -> bool {
+        self.state == KeyState::Down
+    }
+
+    pub fn character(&self) -> Option<char> {
+        match self.key {
+            Some(DecodedKey::Unicode(character)) => Some(character),
+            _ => None,
+        }
+    }
+}
+
+// pc_keyboard's Keyboard is generic over the scancode set, so switching sets at runtime means
+// switching to a different Keyboard. Its layout parameter is unused, we only call `add_byte`.
+enum ScancodeDecoder {
+    Set1(Keyboard<Us104Key, ScancodeSet1>),
+    Set2(Keyboard<Us104Key, ScancodeSet2>),
+}
+impl ScancodeDecoder {
+    fn new(set: ScancodeSet) -> Self {
+        match set {
+            ScancodeSet::Set1 => ScancodeDecoder::Set1(Keyboard::new(Us104Key, ScancodeSet1, HandleControl::Ignore)),
+            ScancodeSet::Set2 => ScancodeDecoder::Set2(Keyboard::new(Us104Key, ScancodeSet2, HandleControl::Ignore)),
+        }
+    }
+
+    fn set(&self) -> ScancodeSet {
+        match self {
+            ScancodeDecoder::Set1(_) => ScancodeSet::Set1,
+            ScancodeDecoder::Set2(_) => ScancodeSet::Set2,
+        }
+    }
+
+    fn add_byte(&mut self, scancode: u8) -> Result<Option<pc_keyboard::KeyEvent>, pc_keyboard::Error> {
+        match self {
+            ScancodeDecoder::Set1(keyboard) => keyboard.add_byte(scancode),
+            ScancodeDecoder::Set2(keyboard) => keyboard.add_byte(scancode),
+        }
+    }
+}
+
+// Turns scancodes into KeyEvents. pc_keyboard does the scancode decoding, but we keep track of the
+// modifiers ourselves so we can put them into every event, and map the keys with the layout
+// selected in `keymap`.
+struct Decoder {
+    scancodes: ScancodeDecoder,
+    modifiers: Modifiers,
+}
+impl Decoder {
+    fn decode(&mut self, scancode: u8) -> Option<KeyEvent> {
+        let set = keymap::scancode_set();
+        if self.scancodes.set() != set {
+            self.scancodes = ScancodeDecoder::new(set);
+        }
+
+        let event = self.scancodes.add_byte(scancode).ok()??;
+        let leds = self.modifiers.leds();
+        let is_modifier = self.modifiers.update(event.code, event.state);
+        if self.modifiers.leds() != leds {
+            // without a PS/2 controller there are no LEDs to update
+            let _ = ps2::set_leds(self.modifiers.leds());
+        }
+
+        let key = if event.state == KeyState::Down && !is_modifier {
+            let modifiers = self.modifiers.to_pc_keyboard();
+            Some(keymap::layout().map_keycode(event.code, &modifiers, HandleControl::Ignore))
+        } else {
+            None
+        };
+        Some(KeyEvent {
+            code: event.code,
+            state: event.state,
+            modifiers: self.modifiers,
+            key,
+        })
+    }
+}
+
+lazy_static! {
+    static ref DECODER: IrqMutex<Decoder> = IrqMutex::new(Decoder {
+        scancodes: ScancodeDecoder::new(ScancodeSet::Set1),
+        modifiers: Modifiers::default(),
+    });
+}
+
+static KEYBOARD_ACTION: IrqAction = IrqAction {
+    name: "keyboard",
+    handler: keyboard_interrupt,
+};
+
+pub fn init() {
+    keymap::init();
+    if let Err(error) = ps2::init() {
+        println!("WARNING: PS/2 controller initialization failed ({:?})", error);
+    }
+    irq::register(irq::KEYBOARD, &KEYBOARD_ACTION).expect("keyboard IRQ handler already registered");
+}
+
+// Handler for IRQ 1
+fn keyboard_interrupt() -> IrqResult {
+    let scancode = match ps2::pending(Device::Keyboard) {
+        Some(scancode) => scancode,
+        None => return IrqResult::NotMine,
+    };
+    if ps2::keyboard_response(scancode) {
+        return IrqResult::Handled;
+    }
+
+    add_scancode(scancode);
+    if let Some(event) = DECODER.lock().decode(scancode) {
+        publish(event);
+    }
+    IrqResult::Handled
+}
+
+// Sends `event` to all subscribers. Called by the keyboard interrupt handler, but other input
+// sources can inject events too.
+pub fn publish(event: KeyEvent) {
+    for (slot, subscribed) in SUBSCRIBERS.iter().zip(SUBSCRIBED.iter()) {
+        if !subscribed.load(Ordering::Acquire) {
+            continue;
+        }
+        if let Ok(subscriber) = slot.try_get() {
+            // a full queue means the subscriber doesn't keep up, it loses this event
+            if subscriber.queue.push(event).is_ok() {
+                subscriber.waker.wake();
+            }
+        }
+    }
+}
+
+// Called by the keyboard interrupt hanlder. Nobody might be listening for the raw scancodes, in
+// that case they are dropped.
 /// Must not block or allocate.
-pub(crate) fn add_scancode(scancode: u8) {
+fn add_scancode(scancode: u8) {
     if let Ok(queue) = SCANCODE_QUEUE.try_get() {
         if let Err(_) = queue.push(scancode) {
             // This happens when you type too fast
@@ -39,8 +290,58 @@ pub(crate) fn add_scancode(scancode: u8) {
         } else {
             WAKER.wake();
         }
-    } else {
-        println!("WARNING: scancode queue uninitialized");
+    }
+}
+
+// A subscription to the decoded keyboard input. Dropping it frees the slot.
+pub struct KeyEventStream {
+    slot: usize,
+}
+impl KeyEventStream {
+    // Returns None if all MAX_SUBSCRIBERS slots are taken
+    pub fn subscribe() -> Option<Self> {
+        let slot = SUBSCRIBED.iter().position(|subscribed| {
+            subscribed.compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire).is_ok()
+        })?;
+
+        // try_init_once fails if a previous subscriber already created the queue, then we reuse it
+        let _ = SUBSCRIBERS[slot].try_init_once(|| Subscriber {
+            queue: ArrayQueue::new(SUBSCRIBER_QUEUE_SIZE),
+            waker: AtomicWaker::new(),
+        });
+        let subscriber = SUBSCRIBERS[slot].try_get().expect("initialized above");
+        // events left over from the previous subscriber
+        while subscriber.queue.pop().is_ok() {}
+
+        Some(KeyEventStream { slot })
+    }
+
+    fn subscriber(&self) -> &'static Subscriber {
+        SUBSCRIBERS[self.slot].try_get().expect("initialized in subscribe")
+    }
+}
+impl Drop for KeyEventStream {
+    fn drop(&mut self) {
+        SUBSCRIBED[self.slot].store(false, Ordering::Release);
+    }
+}
+
+// Same pattern as ScancodeStream::poll_next
+impl Stream for KeyEventStream {
+    type Item = KeyEvent;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<Option<KeyEvent>> {
+        let subscriber = self.subscriber();
+
+        if let Ok(event) = subscriber.queue.pop() {
+            return Poll::Ready(Some(event));
+        }
+
+        subscriber.waker.register(&context.waker());
+        match subscriber.queue.pop() {
+            Ok(event) => Poll::Ready(Some(event)),
+            Err(crossbeam_queue::PopError) => Poll::Pending,
+        }
     }
 }
 
@@ -87,17 +388,34 @@ impl Stream for ScancodeStream {
 }
 
 pub async fn print_keypresses() {
-    let mut scancodes = ScancodeStream::new();
-    let mut keyboard = Keyboard::new(Us104Key, ScancodeSet1, HandleControl::Ignore);
-
-    while let Some(scancode) = scancodes.next().await {
-        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-            if let Some(key) = keyboard.process_keyevent(key_event) {
-                match key {
-                    DecodedKey::Unicode(character) => print!("{}", character),
-                    DecodedKey::RawKey(key) => print!("{:?}", key),
-                }
-            }
+    let mut events = KeyEventStream::subscribe().expect("no free keyboard subscriber slot");
+
+    while let Some(event) = events.next().await {
+        match event.key {
+            Some(DecodedKey::Unicode(character)) => print!("{}", character),
+            Some(DecodedKey::RawKey(key)) => print!("{:?}", key),
+            None => {}
         }
     }
 }
+
+// Tests
+#[test_case]
+fn test_key_event_subscribers() {
+    let waker = futures_util::task::noop_waker();
+    let mut context = Context::from_waker(&waker);
+
+    let mut first = KeyEventStream::subscribe().expect("no free subscriber slot");
+    let mut second = KeyEventStream::subscribe().expect("no free subscriber slot");
+    let event = KeyEvent {
+        code: KeyCode::A,
+        state: KeyState::Down,
+        modifiers: Modifiers { left_shift: true, ..Modifiers::default() },
+        key: Some(DecodedKey::Unicode('A')),
+    };
+    publish(event);
+
+    assert_eq!(Pin::new(&mut first).poll_next(&mut context), Poll::Ready(Some(event)));
+    assert_eq!(Pin::new(&mut second).poll_next(&mut context), Poll::Ready(Some(event)));
+    assert!(Pin::new(&mut first).poll_next(&mut context).is_pending());
+}
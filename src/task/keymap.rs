@@ -0,0 +1,249 @@
+// Keyboard layouts and scancode sets, selectable at runtime
+//
+// Name     Layout
+// us       US 104 key (default)
+// uk       UK 105 key
+// de       German 105 key (QWERTZ)
+// azerty   French AZERTY
+// dvorak   Dvorak 104 key
+// jp       Japanese 109 key
+//
+// The layout can be changed with the `keymap` command. bootloader 0.9 doesn't pass a command line
+// to the kernel, so the command saves the setting in a CMOS byte instead (see time::rtc), which
+// keeps it across reboots and power offs. `init` reads it back at boot:
+//
+// Bits  Meaning
+// 0-3   layout, the index in Layout::ALL
+// 4     scancode set 2
+// 5-7   SAVED_MARK, a byte nobody wrote isn't taken for a setting
+//
+// Without a saved setting the build time defaults apply, the COMETOS_KEYMAP and
+// COMETOS_SCANCODE_SET environment variables (e.g. `COMETOS_KEYMAP=de cargo run`), else us and
+// set 1.
+//
+// Most PS/2 controllers translate the keyboard's scancode set 2 to set 1, which is what we decode
+// by default. On controllers that don't translate, select set 2 with `keymap set2`.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+use pc_keyboard::{
+    layouts::{Azerty, Dvorak104Key, Jis109Key, Uk105Key, Us104Key},
+    DecodedKey,
+    HandleControl,
+    KeyCode,
+    KeyboardLayout,
+    Modifiers,
+};
+use crate::{println, time::rtc};
+
+static LAYOUT: AtomicU8 = AtomicU8::new(Layout::Us104 as u8);
+static SCANCODE_SET: AtomicU8 = AtomicU8::new(ScancodeSet::Set1 as u8);
+
+const SAVED_MARK: u8 = 0b101 << 5;
+const MARK_MASK: u8 = 0b111 << 5;
+const SET2_BIT: u8 = 1 << 4;
+const LAYOUT_MASK: u8 = 0b1111;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Layout {
+    Us104,
+    Uk105,
+    De105,
+    Azerty,
+    Dvorak104,
+    Jis109,
+}
+impl Layout {
+    pub const ALL: [Layout; 6] = [
+        Layout::Us104,
+        Layout::Uk105,
+        Layout::De105,
+        Layout::Azerty,
+        Layout::Dvorak104,
+        Layout::Jis109,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Layout::Us104 => "us",
+            Layout::Uk105 => "uk",
+            Layout::De105 => "de",
+            Layout::Azerty => "azerty",
+            Layout::Dvorak104 => "dvorak",
+            Layout::Jis109 => "jp",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Layout> {
+        Layout::ALL.iter().copied().find(|layout| layout.name() == name)
+    }
+
+    pub fn map_keycode(&self, keycode: KeyCode, modifiers: &Modifiers, handle_ctrl: HandleControl) -> DecodedKey {
+        match self {
+            Layout::Us104 => Us104Key::map_keycode(keycode, modifiers, handle_ctrl),
+            Layout::Uk105 => Uk105Key::map_keycode(keycode, modifiers, handle_ctrl),
+            Layout::De105 => De105Key::map_keycode(keycode, modifiers, handle_ctrl),
+            Layout::Azerty => Azerty::map_keycode(keycode, modifiers, handle_ctrl),
+            Layout::Dvorak104 => Dvorak104Key::map_keycode(keycode, modifiers, handle_ctrl),
+            Layout::Jis109 => Jis109Key::map_keycode(keycode, modifiers, handle_ctrl),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ScancodeSet {
+    Set1 = 1,
+    Set2 = 2,
+}
+
+pub fn layout() -> Layout {
+    let layout = LAYOUT.load(Ordering::Relaxed);
+    Layout::ALL.iter().copied().find(|l| *l as u8 == layout).unwrap_or(Layout::Us104)
+}
+
+pub fn set_layout(layout: Layout) {
+    LAYOUT.store(layout as u8, Ordering::Relaxed);
+}
+
+pub fn scancode_set() -> ScancodeSet {
+    match SCANCODE_SET.load(Ordering::Relaxed) {
+        2 => ScancodeSet::Set2,
+        _ => ScancodeSet::Set1,
+    }
+}
+
+// The keyboard decoder switches on the next scancode, a key sequence that is in progress is lost
+pub fn set_scancode_set(set: ScancodeSet) {
+    SCANCODE_SET.store(set as u8, Ordering::Relaxed);
+}
+
+// Applies the setting saved by `save`, or the build time defaults without one
+pub fn init() {
+    let saved = rtc::read_setting(rtc::KEYMAP_SETTING);
+    if saved & MARK_MASK == SAVED_MARK {
+        if let Some(layout) = Layout::ALL.get((saved & LAYOUT_MASK) as usize) {
+            set_layout(*layout);
+        }
+        set_scancode_set(if saved & SET2_BIT != 0 { ScancodeSet::Set2 } else { ScancodeSet::Set1 });
+        return;
+    }
+
+    if let Some(name) = option_env!("COMETOS_KEYMAP") {
+        match Layout::from_name(name) {
+            Some(layout) => set_layout(layout),
+            None => println!("WARNING: unknown keymap {:?}, using {}", name, layout().name()),
+        }
+    }
+    match option_env!("COMETOS_SCANCODE_SET") {
+        Some("1") | None => {}
+        Some("2") => set_scancode_set(ScancodeSet::Set2),
+        Some(other) => println!("WARNING: unknown scancode set {:?}, using set 1", other),
+    }
+}
+
+// Saves the layout and scancode set for the next boot
+pub fn save() {
+    let index = Layout::ALL.iter().position(|layout| *layout == self::layout()).unwrap_or(0) as u8;
+    let set2 = if scancode_set() == ScancodeSet::Set2 { SET2_BIT } else { 0 };
+    rtc::write_setting(rtc::KEYMAP_SETTING, SAVED_MARK | set2 | index);
+}
+
+// German QWERTZ layout. pc-keyboard names the key codes after their US position, so only the keys
+// that differ from the US layout are mapped here, everything else goes to Us104Key.
+pub struct De105Key;
+impl KeyboardLayout for De105Key {
+    fn map_keycode(keycode: KeyCode, modifiers: &Modifiers, handle_ctrl: HandleControl) -> DecodedKey {
+        let shifted = modifiers.is_shifted();
+        let pick = |plain: char, shift: char| DecodedKey::Unicode(if shifted { shift } else { plain });
+        let letter = |lower: char, upper: char| DecodedKey::Unicode(if modifiers.is_caps() { upper } else { lower });
+
+        if modifiers.alt_gr {
+            let character = match keycode {
+                KeyCode::Q => Some('@'),
+                KeyCode::E => Some('€'),
+                KeyCode::M => Some('µ'),
+                KeyCode::Key2 => Some('²'),
+                KeyCode::Key3 => Some('³'),
+                KeyCode::Key7 => Some('{'),
+                KeyCode::Key8 => Some('['),
+                KeyCode::Key9 => Some(']'),
+                KeyCode::Key0 => Some('}'),
+                KeyCode::Minus => Some('\\'),
+                KeyCode::BracketSquareRight => Some('~'),
+                KeyCode::HashTilde => Some('|'),
+                _ => None,
+            };
+            if let Some(character) = character {
+                return DecodedKey::Unicode(character);
+            }
+        }
+
+        match keycode {
+            // Y and Z swap places, the US layout still does the Ctrl and Caps Lock handling
+            KeyCode::Y => Us104Key::map_keycode(KeyCode::Z, modifiers, handle_ctrl),
+            KeyCode::Z => Us104Key::map_keycode(KeyCode::Y, modifiers, handle_ctrl),
+            KeyCode::BackTick => pick('^', '°'),
+            KeyCode::Key2 => pick('2', '"'),
+            KeyCode::Key3 => pick('3', '§'),
+            KeyCode::Key6 => pick('6', '&'),
+            KeyCode::Key7 => pick('7', '/'),
+            KeyCode::Key8 => pick('8', '('),
+            KeyCode::Key9 => pick('9', ')'),
+            KeyCode::Key0 => pick('0', '='),
+            KeyCode::Minus => pick('ß', '?'),
+            KeyCode::Equals => pick('´', '`'),
+            KeyCode::BracketSquareLeft => letter('ü', 'Ü'),
+            KeyCode::BracketSquareRight => pick('+', '*'),
+            KeyCode::SemiColon => letter('ö', 'Ö'),
+            KeyCode::Quote => letter('ä', 'Ä'),
+            // the key left of Enter
+            KeyCode::BackSlash => pick('#', '\''),
+            // the key between left Shift and Y
+            KeyCode::HashTilde => pick('<', '>'),
+            KeyCode::Comma => pick(',', ';'),
+            KeyCode::Fullstop => pick('.', ':'),
+            KeyCode::Slash => pick('-', '_'),
+            _ => Us104Key::map_keycode(keycode, modifiers, handle_ctrl),
+        }
+    }
+}
+
+// Tests
+#[test_case]
+fn test_german_layout() {
+    let mut modifiers = Modifiers {
+        lshift: false,
+        rshift: false,
+        lctrl: false,
+        rctrl: false,
+        numlock: true,
+        capslock: false,
+        alt_gr: false,
+    };
+    let map = |code, modifiers: &Modifiers| Layout::De105.map_keycode(code, modifiers, HandleControl::Ignore);
+
+    assert_eq!(map(KeyCode::Z, &modifiers), DecodedKey::Unicode('y'));
+    assert_eq!(map(KeyCode::SemiColon, &modifiers), DecodedKey::Unicode('ö'));
+    modifiers.lshift = true;
+    assert_eq!(map(KeyCode::Key7, &modifiers), DecodedKey::Unicode('/'));
+    modifiers.lshift = false;
+    modifiers.alt_gr = true;
+    assert_eq!(map(KeyCode::Q, &modifiers), DecodedKey::Unicode('@'));
+}
+
+#[test_case]
+fn test_saved_setting() {
+    let (previous, before) = (rtc::read_setting(rtc::KEYMAP_SETTING), (layout(), scancode_set()));
+    set_layout(Layout::Dvorak104);
+    set_scancode_set(ScancodeSet::Set2);
+    save();
+    set_layout(Layout::Us104);
+    set_scancode_set(ScancodeSet::Set1);
+    init();
+    assert_eq!((layout(), scancode_set()), (Layout::Dvorak104, ScancodeSet::Set2));
+
+    rtc::write_setting(rtc::KEYMAP_SETTING, previous);
+    set_layout(before.0);
+    set_scancode_set(before.1);
+}
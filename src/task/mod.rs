@@ -13,7 +13,12 @@ use core::{
 use alloc::boxed::Box;
 
 pub mod keyboard;
+pub mod keymap;
+pub mod mouse;
+pub mod serial;
 pub mod executor;
+pub mod join;
+pub mod timer;
 
 // wrapper around a pinned, heap-allocated, and dynamically dispatched future with the empty type
 // as output.
@@ -23,27 +28,54 @@ pub mod executor;
 // Task type. This is important because each async fn has its own type and we want to be able to
 // create multiple different tasks.
 //
+// The future has to be Send, tasks can be spawned from any CPU through a Spawner.
+//
 // Pin<Box> type ensures that a value can't be moved in memory by placing it on the heap and
 // preventing the creation of &mut references to it. This is important because futures generated by
 // async/await might be self-referential, i.e., contain pointers to themselves that would be
 // invalidated when the future is moved.
 pub struct Task {
     id: TaskId,
-    future: Pin<Box<dyn Future<Output = ()>>>,
+    priority: Priority,
+    future: Pin<Box<dyn Future<Output = ()> + Send>>,
 }
 impl Task {
-    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+    // Tasks start out as Priority::Interactive
+    pub fn new(future: impl Future<Output = ()> + Send + 'static) -> Task {
         Task {
             id: TaskId::new(),
+            priority: Priority::Interactive,
             future: Box::pin(future),
         }
     }
 
+    pub fn with_priority(mut self, priority: Priority) -> Task {
+        self.priority = priority;
+        self
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
         self.future.as_mut().poll(context)
     }
 }
 
+// The executor always polls a ready task of the highest priority first, see executor::Executor
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    // handles keyboard or mouse input, should only ever run briefly
+    Input,
+    Interactive,
+    Background,
+}
+impl Priority {
+    // Highest first
+    pub const ALL: [Priority; 3] = [Priority::Input, Priority::Interactive, Priority::Background];
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 struct TaskId(i64);
 impl TaskId {
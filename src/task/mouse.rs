@@ -0,0 +1,209 @@
+use crate::{
+    io::ps2::{self, Device, Ps2Error},
+    memory::irq::{self, IrqAction, IrqResult},
+    sync::IrqMutex,
+};
+use conquer_once::spin::OnceCell;
+use crossbeam_queue::ArrayQueue;
+use core::{
+    pin::Pin,
+    sync::atomic::{
+        AtomicBool,
+        Ordering,
+    },
+    task::{
+        Poll,
+        Context
+    },
+};
+use futures_util::{
+    stream::Stream,
+    task::AtomicWaker,
+};
+
+// A PS/2 mouse sends 3 byte packets, or 4 byte packets once it's switched to IntelliMouse mode:
+//
+// Byte  Bits
+// 0     0 left, 1 right, 2 middle, 3 always set, 4 X sign, 5 Y sign, 6 X overflow, 7 Y overflow
+// 1     X movement (low 8 bits of a 9 bit two's complement value)
+// 2     Y movement, positive is up
+// 3     wheel movement, signed (IntelliMouse only)
+//
+// IntelliMouse mode is unlocked by setting the sample rate to 200, 100 and 80 in a row, after that
+// the mouse reports device id 3 instead of 0.
+
+static EVENT_QUEUE: OnceCell<ArrayQueue<MouseEvent>> = OnceCell::uninit();
+static WAKER: AtomicWaker = AtomicWaker::new();
+static HAS_WHEEL: AtomicBool = AtomicBool::new(false);
+static PACKET: IrqMutex<Packet> = IrqMutex::new(Packet::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MouseButtons {
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MouseEvent {
+    // movement since the last event, positive `dy` is up
+    pub dx: i16,
+    pub dy: i16,
+    // positive is scrolling down, always 0 without a wheel
+    pub wheel: i8,
+    pub buttons: MouseButtons,
+}
+
+// Collects the bytes of a packet
+struct Packet {
+    bytes: [u8; 4],
+    received: usize,
+}
+impl Packet {
+    const fn new() -> Self {
+        Packet {
+            bytes: [0; 4],
+            received: 0,
+        }
+    }
+
+    fn add_byte(&mut self, byte: u8, size: usize) -> Option<MouseEvent> {
+        // bit 3 of the first byte is always set, if it isn't we lost a byte and wait for the next
+        // packet to start
+        if self.received == 0 && byte & (1 << 3) == 0 {
+            return None;
+        }
+        self.bytes[self.received] = byte;
+        self.received += 1;
+        if self.received < size {
+            return None;
+        }
+        self.received = 0;
+        parse_packet(&self.bytes[..size])
+    }
+}
+
+fn parse_packet(bytes: &[u8]) -> Option<MouseEvent> {
+    let flags = bytes[0];
+    // the movement is garbage if it overflowed
+    if flags & 0b1100_0000 != 0 {
+        return None;
+    }
+
+    // the sign bits are the 9th bit of the movement
+    let dx = bytes[1] as i16 - (((flags as i16) << 4) & 0x100);
+    let dy = bytes[2] as i16 - (((flags as i16) << 3) & 0x100);
+    let wheel = bytes.get(3).map_or(0, |&wheel| wheel as i8);
+
+    Some(MouseEvent {
+        dx,
+        dy,
+        wheel,
+        buttons: MouseButtons {
+            left: flags & 0b001 != 0,
+            right: flags & 0b010 != 0,
+            middle: flags & 0b100 != 0,
+        },
+    })
+}
+
+static MOUSE_ACTION: IrqAction = IrqAction {
+    name: "mouse",
+    handler: mouse_interrupt,
+};
+
+// Sets up the mouse on the second PS/2 port and registers the IRQ 12 handler. Must be called after
+// `io::ps2::init`.
+pub fn init() -> Result<(), Ps2Error> {
+    if !ps2::info().map_or(false, |info| info.mouse_port) {
+        return Err(Ps2Error::NotInitialized);
+    }
+
+    // defaults: 100 samples per second, data reporting off
+    ps2::send_to_second_port(0xF6)?;
+
+    for rate in [200, 100, 80] {
+        ps2::send_to_second_port(0xF3)?;
+        ps2::send_to_second_port(rate)?;
+    }
+    ps2::send_to_second_port(0xF2)?;
+    HAS_WHEEL.store(ps2::read_response()? == 3, Ordering::Relaxed);
+
+    ps2::send_to_second_port(0xF4)?;
+    irq::register(irq::MOUSE, &MOUSE_ACTION).expect("mouse IRQ handler already registered");
+    Ok(())
+}
+
+pub fn has_wheel() -> bool {
+    HAS_WHEEL.load(Ordering::Relaxed)
+}
+
+// Handler for IRQ 12
+fn mouse_interrupt() -> IrqResult {
+    let byte = match ps2::pending(Device::Mouse) {
+        Some(byte) => byte,
+        None => return IrqResult::NotMine,
+    };
+
+    let size = if has_wheel() { 4 } else { 3 };
+    if let Some(event) = PACKET.lock().add_byte(byte, size) {
+        add_event(event);
+    }
+    IrqResult::Handled
+}
+
+// Called by the mouse interrupt handler
+fn add_event(event: MouseEvent) {
+    if let Ok(queue) = EVENT_QUEUE.try_get() {
+        if queue.push(event).is_ok() {
+            WAKER.wake();
+        }
+        // else the queue is full and the event is dropped, like in `keyboard::add_scancode`
+    }
+}
+
+pub struct MouseStream {
+    _private: (),
+}
+impl MouseStream {
+    pub fn new() -> Self {
+        EVENT_QUEUE.try_init_once(|| ArrayQueue::new(100)).expect("MouseStream::new should only be called once");
+        MouseStream { _private: () }
+    }
+}
+
+// See ScancodeStream::poll_next
+impl Stream for MouseStream {
+    type Item = MouseEvent;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<Option<MouseEvent>> {
+        let queue = EVENT_QUEUE.try_get().expect("not initialized");
+
+        if let Ok(event) = queue.pop() {
+            return Poll::Ready(Some(event));
+        }
+
+        WAKER.register(&context.waker());
+        match queue.pop() {
+            Ok(event) => Poll::Ready(Some(event)),
+            Err(crossbeam_queue::PopError) => Poll::Pending,
+        }
+    }
+}
+
+// Tests
+#[test_case]
+fn test_parse_mouse_packet() {
+    // left button, moved 1 to the left and 2 up, scrolled up once
+    let mut packet = Packet::new();
+    assert_eq!(packet.add_byte(0b0001_1001, 4), None);
+    assert_eq!(packet.add_byte(0xFF, 4), None);
+    assert_eq!(packet.add_byte(2, 4), None);
+    let event = packet.add_byte(0xFF, 4).expect("complete packet");
+    assert_eq!((event.dx, event.dy, event.wheel), (-1, 2, -1));
+    assert!(event.buttons.left && !event.buttons.right);
+
+    // a byte without the always-set bit can't start a packet
+    assert_eq!(packet.add_byte(0, 3), None);
+    assert_eq!(packet.received, 0);
+}
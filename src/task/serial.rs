@@ -0,0 +1,278 @@
+use crate::{
+    io::serial,
+    memory::irq::{self, IrqAction, IrqResult},
+    sync::IrqMutex,
+};
+use super::keyboard::{self, KeyEvent, Modifiers};
+use conquer_once::spin::OnceCell;
+use crossbeam_queue::ArrayQueue;
+use core::{
+    pin::Pin,
+    task::{
+        Poll,
+        Context
+    },
+};
+use futures_util::{
+    stream::Stream,
+    task::AtomicWaker,
+};
+use pc_keyboard::{DecodedKey, KeyCode, KeyState};
+
+// Input from COM1. Every received byte goes to the SerialStream, if one exists, and through a small
+// terminal decoder that turns the characters and VT100 escape sequences into KeyEvents for the
+// keyboard subscribers. That way the shell works the same over `-serial stdio` as with a keyboard.
+// The first received byte also turns on the console mirroring of `io::serial`, so the output shows
+// up on the other end.
+//
+// Sequence            Key
+// ESC [ A             ArrowUp
+// ESC [ B             ArrowDown
+// ESC [ C             ArrowRight
+// ESC [ D             ArrowLeft
+// ESC [ H, ESC [ 1 ~  Home
+// ESC [ F, ESC [ 4 ~  End
+// ESC [ 2 ~           Insert
+// ESC [ 3 ~           Delete
+// ESC [ 5 ~           PageUp
+// ESC [ 6 ~           PageDown
+//
+// ESC O instead of ESC [ works for the first six. Terminals send Enter as \r and Backspace as 0x7F,
+// they become '\n' and '\x08' like on the keyboard. Only ASCII is translated, the VGA buffer can't
+// show anything else anyway. A lone ESC is only reported as the Escape key once the next byte
+// arrives, there's no way to tell it apart from the start of a sequence before that.
+
+static BYTE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+static WAKER: AtomicWaker = AtomicWaker::new();
+static DECODER: IrqMutex<EscapeDecoder> = IrqMutex::new(EscapeDecoder::new());
+
+static SERIAL_ACTION: IrqAction = IrqAction {
+    name: "serial",
+    handler: serial_interrupt,
+};
+
+pub fn init() {
+    serial::enable_receive_interrupt();
+    irq::register(irq::COM1, &SERIAL_ACTION).expect("serial IRQ handler already registered");
+}
+
+// Handler for IRQ 4
+fn serial_interrupt() -> IrqResult {
+    let mut result = IrqResult::NotMine;
+    // the UART raises one interrupt for everything that arrived since we last read
+    while let Some(byte) = serial::try_read() {
+        result = IrqResult::Handled;
+        serial::set_mirror_console(true);
+
+        add_byte(byte);
+        DECODER.lock().feed(byte, &mut |code, key, modifiers| press(code, key, modifiers));
+    }
+    result
+}
+
+// Called by the serial interrupt handler
+fn add_byte(byte: u8) {
+    if let Ok(queue) = BYTE_QUEUE.try_get() {
+        if queue.push(byte).is_ok() {
+            WAKER.wake();
+        }
+    }
+}
+
+// A terminal only sends key presses, so every press is followed by a release
+fn press(code: KeyCode, key: DecodedKey, modifiers: Modifiers) {
+    let event = KeyEvent { code, state: KeyState::Down, modifiers, key: Some(key) };
+    keyboard::publish(event);
+    keyboard::publish(KeyEvent { state: KeyState::Up, key: None, ..event });
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EscapeState {
+    Ground,
+    // after ESC
+    Escape,
+    // after ESC [, with the numeric parameter so far
+    Csi(u8),
+    // after ESC O
+    Ss3,
+}
+
+struct EscapeDecoder {
+    state: EscapeState,
+}
+impl EscapeDecoder {
+    const fn new() -> Self {
+        EscapeDecoder { state: EscapeState::Ground }
+    }
+
+    fn feed(&mut self, byte: u8, emit: &mut dyn FnMut(KeyCode, DecodedKey, Modifiers)) {
+        match (self.state, byte) {
+            (EscapeState::Escape, b'[') => self.state = EscapeState::Csi(0),
+            (EscapeState::Escape, b'O') => self.state = EscapeState::Ss3,
+            (EscapeState::Escape, _) => {
+                // not a sequence, the ESC was the Escape key
+                self.state = EscapeState::Ground;
+                emit(KeyCode::Escape, DecodedKey::Unicode('\x1b'), Modifiers::default());
+                self.feed(byte, emit);
+            }
+            (EscapeState::Csi(parameter), b'0'..=b'9') => {
+                self.state = EscapeState::Csi(parameter.saturating_mul(10).saturating_add(byte - b'0'));
+            }
+            (EscapeState::Csi(parameter), _) => {
+                self.state = EscapeState::Ground;
+                let code = match (byte, parameter) {
+                    (b'~', 1) => Some(KeyCode::Home),
+                    (b'~', 2) => Some(KeyCode::Insert),
+                    (b'~', 3) => Some(KeyCode::Delete),
+                    (b'~', 4) => Some(KeyCode::End),
+                    (b'~', 5) => Some(KeyCode::PageUp),
+                    (b'~', 6) => Some(KeyCode::PageDown),
+                    (byte, _) => cursor_key(byte),
+                };
+                if let Some(code) = code {
+                    emit(code, raw_key(code), Modifiers::default());
+                }
+            }
+            (EscapeState::Ss3, _) => {
+                self.state = EscapeState::Ground;
+                if let Some(code) = cursor_key(byte) {
+                    emit(code, raw_key(code), Modifiers::default());
+                }
+            }
+            (EscapeState::Ground, 0x1B) => self.state = EscapeState::Escape,
+            (EscapeState::Ground, _) => {
+                if let Some((code, character, modifiers)) = character_key(byte) {
+                    emit(code, DecodedKey::Unicode(character), modifiers);
+                }
+            }
+        }
+    }
+}
+
+fn cursor_key(byte: u8) -> Option<KeyCode> {
+    match byte {
+        b'A' => Some(KeyCode::ArrowUp),
+        b'B' => Some(KeyCode::ArrowDown),
+        b'C' => Some(KeyCode::ArrowRight),
+        b'D' => Some(KeyCode::ArrowLeft),
+        b'H' => Some(KeyCode::Home),
+        b'F' => Some(KeyCode::End),
+        _ => None,
+    }
+}
+
+// What the US layout decodes these keys to
+fn raw_key(code: KeyCode) -> DecodedKey {
+    match code {
+        KeyCode::Delete => DecodedKey::Unicode('\x7f'),
+        code => DecodedKey::RawKey(code),
+    }
+}
+
+const LETTERS: [KeyCode; 26] = [
+    KeyCode::A, KeyCode::B, KeyCode::C, KeyCode::D, KeyCode::E, KeyCode::F, KeyCode::G,
+    KeyCode::H, KeyCode::I, KeyCode::J, KeyCode::K, KeyCode::L, KeyCode::M, KeyCode::N,
+    KeyCode::O, KeyCode::P, KeyCode::Q, KeyCode::R, KeyCode::S, KeyCode::T, KeyCode::U,
+    KeyCode::V, KeyCode::W, KeyCode::X, KeyCode::Y, KeyCode::Z,
+];
+const DIGITS: [KeyCode; 10] = [
+    KeyCode::Key0, KeyCode::Key1, KeyCode::Key2, KeyCode::Key3, KeyCode::Key4,
+    KeyCode::Key5, KeyCode::Key6, KeyCode::Key7, KeyCode::Key8, KeyCode::Key9,
+];
+// The characters on the digit keys with Shift, in the order of DIGITS
+const SHIFTED_DIGITS: &[u8; 10] = b")!@#$%^&*(";
+// Punctuation of the US layout: unshifted, shifted, key
+const PUNCTUATION: [(u8, u8, KeyCode); 11] = [
+    (b'-', b'_', KeyCode::Minus),
+    (b'=', b'+', KeyCode::Equals),
+    (b'[', b'{', KeyCode::BracketSquareLeft),
+    (b']', b'}', KeyCode::BracketSquareRight),
+    (b'\\', b'|', KeyCode::BackSlash),
+    (b';', b':', KeyCode::SemiColon),
+    (b'\'', b'"', KeyCode::Quote),
+    (b',', b'<', KeyCode::Comma),
+    (b'.', b'>', KeyCode::Fullstop),
+    (b'/', b'?', KeyCode::Slash),
+    (b'`', b'~', KeyCode::BackTick),
+];
+
+// The key (on a US keyboard) and modifiers that type the ASCII character `byte`
+fn character_key(byte: u8) -> Option<(KeyCode, char, Modifiers)> {
+    let shift = Modifiers { left_shift: true, ..Modifiers::default() };
+    let ctrl = Modifiers { left_ctrl: true, ..Modifiers::default() };
+    let none = Modifiers::default();
+
+    let key = match byte {
+        b'\r' | b'\n' => (KeyCode::Enter, '\n', none),
+        0x7F | 0x08 => (KeyCode::Backspace, '\x08', none),
+        b'\t' => (KeyCode::Tab, '\t', none),
+        b' ' => (KeyCode::Spacebar, ' ', none),
+        b'a'..=b'z' => (LETTERS[(byte - b'a') as usize], byte as char, none),
+        b'A'..=b'Z' => (LETTERS[(byte - b'A') as usize], byte as char, shift),
+        b'0'..=b'9' => (DIGITS[(byte - b'0') as usize], byte as char, none),
+        // Ctrl+letter, decoded like the keyboard does with HandleControl::Ignore
+        0x01..=0x1A => (LETTERS[(byte - 1) as usize], (b'a' + byte - 1) as char, ctrl),
+        _ => {
+            if let Some(digit) = SHIFTED_DIGITS.iter().position(|&shifted| shifted == byte) {
+                (DIGITS[digit], byte as char, shift)
+            } else {
+                let (plain, _, code) = PUNCTUATION
+                    .iter()
+                    .find(|(plain, shifted, _)| *plain == byte || *shifted == byte)?;
+                (*code, byte as char, if *plain == byte { none } else { shift })
+            }
+        }
+    };
+    Some(key)
+}
+
+// The raw bytes received on COM1
+pub struct SerialStream {
+    _private: (),
+}
+impl SerialStream {
+    pub fn new() -> Self {
+        BYTE_QUEUE.try_init_once(|| ArrayQueue::new(100)).expect("SerialStream::new should only be called once");
+        SerialStream { _private: () }
+    }
+}
+
+// See ScancodeStream::poll_next
+impl Stream for SerialStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<Option<u8>> {
+        let queue = BYTE_QUEUE.try_get().expect("not initialized");
+
+        if let Ok(byte) = queue.pop() {
+            return Poll::Ready(Some(byte));
+        }
+
+        WAKER.register(&context.waker());
+        match queue.pop() {
+            Ok(byte) => Poll::Ready(Some(byte)),
+            Err(crossbeam_queue::PopError) => Poll::Pending,
+        }
+    }
+}
+
+// Tests
+#[test_case]
+fn test_escape_sequences() {
+    use alloc::vec::Vec;
+
+    let mut decoder = EscapeDecoder::new();
+    let mut keys = Vec::new();
+    for &byte in b"\x1b[Ab\x1b[3~\x1bx\r" {
+        decoder.feed(byte, &mut |code, key, modifiers| keys.push((code, key, modifiers.shift())));
+    }
+
+    assert_eq!(keys, [
+        (KeyCode::ArrowUp, DecodedKey::RawKey(KeyCode::ArrowUp), false),
+        (KeyCode::B, DecodedKey::Unicode('b'), false),
+        (KeyCode::Delete, DecodedKey::Unicode('\x7f'), false),
+        (KeyCode::Escape, DecodedKey::Unicode('\x1b'), false),
+        (KeyCode::X, DecodedKey::Unicode('x'), false),
+        (KeyCode::Enter, DecodedKey::Unicode('\n'), false),
+    ]);
+}
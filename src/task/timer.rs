@@ -0,0 +1,230 @@
+use crate::{sync::IrqMutex, time};
+use alloc::collections::BTreeMap;
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{
+        AtomicU64,
+        Ordering,
+    },
+    task::{
+        Context,
+        Poll,
+        Waker,
+    },
+    time::Duration,
+};
+use futures_util::stream::Stream;
+use lazy_static::lazy_static;
+
+// The timer queue maps a (deadline, timer id) pair to the waker of the task waiting for it. The
+// deadline is measured in system ticks. BTreeMap keeps the entries sorted, so the expired timers
+// are always at the front of the map.
+//
+// The timer interrupt only increments the tick counter, it must not touch this queue because
+// removing entries from a BTreeMap deallocates and the interrupted code might hold the allocator
+// lock. Instead, the executor calls `wake_expired` every time it wakes up, and since every tick
+// wakes the CPU from `hlt`, expired timers are handled within one tick. Waking a task pushes its
+// id into the task_queue through its TaskWaker, so sleeping tasks are woken like any other task.
+lazy_static! {
+    static ref TIMERS: IrqMutex<BTreeMap<(u64, u64), Waker>> = IrqMutex::new(BTreeMap::new());
+}
+// Earliest deadline in TIMERS, lets us check for expired timers without taking the lock
+static NEXT_DEADLINE: AtomicU64 = AtomicU64::new(u64::MAX);
+
+// Wakes all tasks whose timer expired. Called by the executor.
+pub(crate) fn wake_expired() {
+    let now = time::ticks();
+    if now < NEXT_DEADLINE.load(Ordering::Acquire) {
+        return;
+    }
+
+    let expired = {
+        let mut timers = TIMERS.lock();
+        let pending = timers.split_off(&(now + 1, 0));
+        let expired = core::mem::replace(&mut *timers, pending);
+        let next = timers.keys().next().map_or(u64::MAX, |(deadline, _)| *deadline);
+        NEXT_DEADLINE.store(next, Ordering::Release);
+        expired
+    };
+
+    for (_, waker) in expired {
+        waker.wake();
+    }
+}
+
+// Whether there are timers the executor should handle before going to sleep
+pub(crate) fn has_expired() -> bool {
+    time::ticks() >= NEXT_DEADLINE.load(Ordering::Acquire)
+}
+
+// Rounds up, sleeping for a bit longer is fine but waking up early is not
+fn duration_to_ticks(duration: Duration) -> u64 {
+    let frequency = time::frequency().max(1) as u128;
+    let ticks = (duration.as_nanos() * frequency + 999_999_999) / 1_000_000_000;
+    ticks as u64
+}
+
+// Future that completes once the system tick reached `deadline`
+pub struct Sleep {
+    deadline: u64,
+    id: u64,
+}
+impl Sleep {
+    fn until(deadline: u64) -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        Sleep {
+            deadline,
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    fn key(&self) -> (u64, u64) {
+        (self.deadline, self.id)
+    }
+
+    fn unregister(&self) {
+        TIMERS.lock().remove(&self.key());
+    }
+}
+
+// Just like ScancodeStream::poll_next, we check the condition again after registering the waker.
+// A tick might pass between the first check and the registration, in that case the timer is
+// already expired when it's inserted and the executor wakes us on its next `wake_expired`.
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<()> {
+        if time::ticks() >= self.deadline {
+            self.unregister();
+            return Poll::Ready(());
+        }
+
+        let key = self.key();
+        TIMERS.lock().insert(key, context.waker().clone());
+        NEXT_DEADLINE.fetch_min(key.0, Ordering::AcqRel);
+
+        if time::ticks() >= self.deadline {
+            self.unregister();
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.unregister();
+    }
+}
+
+pub fn sleep(duration: Duration) -> Sleep {
+    Sleep::until(time::ticks() + duration_to_ticks(duration).max(1))
+}
+
+// Stream that yields once every `period`. If the consumer falls behind, missed ticks are skipped
+// instead of being yielded in a burst.
+pub struct Interval {
+    period: u64,
+    sleep: Sleep,
+}
+impl Stream for Interval {
+    type Item = ();
+
+    fn poll_next(mut self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<Option<()>> {
+        match Pin::new(&mut self.sleep).poll(context) {
+            Poll::Ready(()) => {
+                let now = time::ticks();
+                let mut next = self.sleep.deadline + self.period;
+                if next <= now {
+                    next = now + self.period;
+                }
+                self.sleep = Sleep::until(next);
+                Poll::Ready(Some(()))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+// The first item is yielded after one `period`
+pub fn interval(period: Duration) -> Interval {
+    let period = duration_to_ticks(period).max(1);
+    Interval {
+        period,
+        sleep: Sleep::until(time::ticks() + period),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+// Future that resolves to Err(Elapsed) if `future` didn't complete before the timeout
+pub struct Timeout<F> {
+    future: F,
+    sleep: Sleep,
+}
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<Self::Output> {
+        // `future` is structurally pinned, we never move it out of `self`. `sleep` is Unpin.
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+
+        if let Poll::Ready(output) = future.poll(context) {
+            return Poll::Ready(Ok(output));
+        }
+        match Pin::new(&mut this.sleep).poll(context) {
+            Poll::Ready(()) => Poll::Ready(Err(Elapsed)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+pub fn timeout<F: Future>(future: F, duration: Duration) -> Timeout<F> {
+    Timeout {
+        future,
+        sleep: sleep(duration),
+    }
+}
+
+// Tests
+#[test_case]
+fn test_sleep_wakes_task() {
+    use alloc::{sync::Arc, task::Wake};
+    use core::sync::atomic::AtomicBool;
+
+    struct FlagWaker(AtomicBool);
+    impl Wake for FlagWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    let flag = Arc::new(FlagWaker(AtomicBool::new(false)));
+    let waker = Waker::from(flag.clone());
+    let mut context = Context::from_waker(&waker);
+
+    let mut sleep = sleep(Duration::from_millis(5));
+    assert!(Pin::new(&mut sleep).poll(&mut context).is_pending());
+    while !flag.0.load(Ordering::SeqCst) {
+        x86_64::instructions::hlt();
+        wake_expired();
+    }
+    assert!(Pin::new(&mut sleep).poll(&mut context).is_ready());
+}
+
+#[test_case]
+fn test_timeout_elapses() {
+    let waker = futures_util::task::noop_waker();
+    let mut context = Context::from_waker(&waker);
+
+    let mut timeout = timeout(core::future::pending::<()>(), Duration::from_millis(5));
+    loop {
+        match Pin::new(&mut timeout).poll(&mut context) {
+            Poll::Ready(result) => break assert_eq!(result, Err(Elapsed)),
+            Poll::Pending => x86_64::instructions::hlt(),
+        }
+    }
+}
@@ -0,0 +1,211 @@
+// The HPET (High Precision Event Timer) is a memory mapped timer with a 64 bit (sometimes 32 bit)
+// main counter running at >= 10 MHz and 3-32 comparators that raise an interrupt when the main
+// counter reaches their value.
+//
+// Registers (offsets from the base address, all 64 bits wide)
+//
+// Offset           Name                           Description
+// 0x000            General Capabilities and ID    bits 8-12: number of comparators - 1
+//                                                 bit 13: 64 bit counter
+//                                                 bit 15: legacy replacement route capable
+//                                                 bits 32-63: counter period in femtoseconds
+// 0x010            General Configuration          bit 0: enable counter
+//                                                 bit 1: legacy replacement route
+// 0x020            General Interrupt Status
+// 0x0F0            Main Counter Value
+// 0x100 + 0x20*N   Timer N Config and Capability  bit 2: interrupt enable
+//                                                 bit 3: periodic mode
+//                                                 bit 4: periodic mode capable
+//                                                 bit 6: next comparator write sets the period
+//                                                 bits 9-13: I/O APIC route
+//                                                 bits 32-63: allowed I/O APIC routes
+// 0x108 + 0x20*N   Timer N Comparator Value
+//
+// In legacy replacement mode, comparator 0 replaces the PIT on IRQ 0 and comparator 1 replaces the
+// RTC on IRQ 8. That's the only routing we can use with the 8259 PIC, the other comparators need
+// an I/O APIC.
+
+use core::ptr;
+use conquer_once::spin::OnceCell;
+use x86_64::VirtAddr;
+use crate::{acpi, memory::memory::map_mmio};
+
+const CAPABILITIES: usize = 0x000;
+const CONFIGURATION: usize = 0x010;
+const MAIN_COUNTER: usize = 0x0F0;
+
+const fn timer_configuration(timer: u8) -> usize {
+    0x100 + 0x20 * timer as usize
+}
+const fn timer_comparator(timer: u8) -> usize {
+    0x108 + 0x20 * timer as usize
+}
+
+// General Configuration
+const ENABLE: u64 = 1 << 0;
+const LEGACY_REPLACEMENT: u64 = 1 << 1;
+
+// Timer N Configuration
+const TIMER_INTERRUPT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAPABLE: u64 = 1 << 4;
+const TIMER_SET_PERIOD: u64 = 1 << 6;
+const TIMER_ROUTE_SHIFT: u64 = 9;
+const TIMER_ROUTE_MASK: u64 = 0b11111 << TIMER_ROUTE_SHIFT;
+
+static HPET: OnceCell<Hpet> = OnceCell::uninit();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HpetError {
+    NotPresent,
+    MappingFailed,
+    InvalidPeriod,
+    NoSuchTimer,
+    PeriodicNotSupported,
+    RouteNotSupported,
+}
+
+pub struct Hpet {
+    base: VirtAddr,
+    // length of one counter tick in femtoseconds (10^-15 s)
+    period: u64,
+    timers: u8,
+    is_64bit: bool,
+    legacy_capable: bool,
+}
+
+// Finds the HPET through ACPI, maps its registers and starts the main counter. Must be called after
+// `acpi::init` and `memory::memory::install`.
+pub fn init() -> Result<&'static Hpet, HpetError> {
+    let info = acpi::hpet::find().map_err(|_| HpetError::NotPresent)?;
+    let base = map_mmio(info.base_address, 0x400).map_err(|_| HpetError::MappingFailed)?;
+
+    let capabilities = unsafe { ptr::read_volatile((base + CAPABILITIES).as_ptr::<u64>()) };
+    let period = capabilities >> 32;
+    // the spec limits the period to 100ns
+    if period == 0 || period > 100_000_000 {
+        return Err(HpetError::InvalidPeriod);
+    }
+
+    let hpet = Hpet {
+        base,
+        period,
+        timers: ((capabilities >> 8) & 0b11111) as u8 + 1,
+        is_64bit: capabilities & (1 << 13) != 0,
+        legacy_capable: capabilities & (1 << 15) != 0,
+    };
+
+    // disable all comparators before starting the counter, the firmware might have left some on
+    for timer in 0..hpet.timers {
+        hpet.disable(timer);
+    }
+    hpet.write(CONFIGURATION, hpet.read(CONFIGURATION) | ENABLE);
+
+    HPET.try_init_once(|| hpet).expect("hpet::init should only be called once");
+    Ok(HPET.try_get().unwrap())
+}
+
+pub fn get() -> Option<&'static Hpet> {
+    HPET.try_get().ok()
+}
+
+impl Hpet {
+    fn read(&self, offset: usize) -> u64 {
+        unsafe { ptr::read_volatile((self.base + offset).as_ptr::<u64>()) }
+    }
+
+    fn write(&self, offset: usize, value: u64) {
+        unsafe { ptr::write_volatile((self.base + offset).as_mut_ptr::<u64>(), value) }
+    }
+
+    pub fn counter(&self) -> u64 {
+        self.read(MAIN_COUNTER)
+    }
+
+    // Counter ticks per second
+    pub fn frequency(&self) -> u64 {
+        1_000_000_000_000_000 / self.period
+    }
+
+    pub fn period_femtoseconds(&self) -> u64 {
+        self.period
+    }
+
+    pub fn comparators(&self) -> u8 {
+        self.timers
+    }
+
+    // A 32 bit counter wraps around after a few minutes, too short to be a clocksource
+    pub fn is_64bit(&self) -> bool {
+        self.is_64bit
+    }
+
+    pub fn nanos_to_ticks(&self, nanoseconds: u64) -> u64 {
+        (nanoseconds as u128 * 1_000_000 / self.period as u128) as u64
+    }
+
+    // Routes comparator 0 to IRQ 0 and comparator 1 to IRQ 8, the PIT and RTC stop raising their
+    // interrupts.
+    pub fn enable_legacy_replacement(&self) -> Result<(), HpetError> {
+        if !self.legacy_capable {
+            return Err(HpetError::RouteNotSupported);
+        }
+        self.write(CONFIGURATION, self.read(CONFIGURATION) | LEGACY_REPLACEMENT);
+        Ok(())
+    }
+
+    pub fn legacy_replacement_enabled(&self) -> bool {
+        self.read(CONFIGURATION) & LEGACY_REPLACEMENT != 0
+    }
+
+    // Routes `timer` to input `irq` of the I/O APIC. Not needed in legacy replacement mode for
+    // comparators 0 and 1.
+    pub fn route(&self, timer: u8, irq: u8) -> Result<(), HpetError> {
+        let config = self.timer_config(timer)?;
+        if irq >= 32 || (config >> 32) & (1 << irq) == 0 {
+            return Err(HpetError::RouteNotSupported);
+        }
+        let config = (config & !TIMER_ROUTE_MASK) | ((irq as u64) << TIMER_ROUTE_SHIFT);
+        self.write(timer_configuration(timer), config);
+        Ok(())
+    }
+
+    // Raises a single interrupt `ticks` counter ticks from now
+    pub fn oneshot(&self, timer: u8, ticks: u64) -> Result<(), HpetError> {
+        let config = self.timer_config(timer)?;
+        let config = (config & !TIMER_PERIODIC) | TIMER_INTERRUPT_ENABLE;
+        self.write(timer_configuration(timer), config);
+        self.write(timer_comparator(timer), self.counter().wrapping_add(ticks));
+        Ok(())
+    }
+
+    // Raises an interrupt every `ticks` counter ticks
+    pub fn periodic(&self, timer: u8, ticks: u64) -> Result<(), HpetError> {
+        let config = self.timer_config(timer)?;
+        if config & TIMER_PERIODIC_CAPABLE == 0 {
+            return Err(HpetError::PeriodicNotSupported);
+        }
+
+        // With TIMER_SET_PERIOD, the first comparator write sets the time of the next interrupt
+        // and the second one the period. We do this while the counter keeps running, stopping it
+        // would make it useless as a clocksource.
+        let config = config | TIMER_INTERRUPT_ENABLE | TIMER_PERIODIC | TIMER_SET_PERIOD;
+        self.write(timer_configuration(timer), config);
+        self.write(timer_comparator(timer), self.counter().wrapping_add(ticks));
+        self.write(timer_comparator(timer), ticks);
+        Ok(())
+    }
+
+    pub fn disable(&self, timer: u8) {
+        if let Ok(config) = self.timer_config(timer) {
+            self.write(timer_configuration(timer), config & !(TIMER_INTERRUPT_ENABLE | TIMER_PERIODIC));
+        }
+    }
+
+    fn timer_config(&self, timer: u8) -> Result<u64, HpetError> {
+        if timer >= self.timers {
+            return Err(HpetError::NoSuchTimer);
+        }
+        Ok(self.read(timer_configuration(timer)))
+    }
+}
@@ -0,0 +1,68 @@
+use core::{
+    fmt,
+    ops::{Add, Sub},
+    time::Duration,
+};
+use super::clock_nanos;
+
+// A measurement of the monotonic clock, with nanosecond resolution if the TSC is our clocksource.
+// Like std::time::Instant it's opaque and only useful compared to other instants.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+impl Instant {
+    pub fn now() -> Instant {
+        Instant(clock_nanos())
+    }
+
+    // Saturates to zero if `earlier` is actually later
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        u64::try_from(duration.as_nanos()).ok()
+            .and_then(|nanos| self.0.checked_add(nanos))
+            .map(Instant)
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        u64::try_from(duration.as_nanos()).ok()
+            .and_then(|nanos| self.0.checked_sub(nanos))
+            .map(Instant)
+    }
+
+    // Time since boot (more precisely, since the clocksource was initialized)
+    pub fn since_boot(&self) -> Duration {
+        Duration::from_nanos(self.0)
+    }
+}
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration).expect("overflow when adding duration to instant")
+    }
+}
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, duration: Duration) -> Instant {
+        self.checked_sub(duration).expect("overflow when subtracting duration from instant")
+    }
+}
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, other: Instant) -> Duration {
+        self.duration_since(other)
+    }
+}
+impl fmt::Debug for Instant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Instant({:?})", self.since_boot())
+    }
+}
@@ -0,0 +1,218 @@
+use core::{
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+    time::Duration,
+};
+use spin::RwLock;
+use x86_64::instructions::interrupts;
+use crate::memory::irq::{self, IrqAction, IrqResult};
+
+pub mod pit;
+pub mod tsc;
+pub mod hpet;
+pub mod instant;
+pub mod rtc;
+pub mod system_time;
+
+pub use instant::Instant;
+pub use system_time::{DateTime, SystemTime, UNIX_EPOCH};
+
+// Frequency the system tick runs at after `init`. Higher values give finer sleep granularity at
+// the cost of more interrupts.
+pub const TIMER_FREQUENCY: u32 = 1000;
+
+// Incremented by the timer interrupt handler
+static TICKS: AtomicU64 = AtomicU64::new(0);
+// We accumulate the elapsed nanoseconds instead of computing them from TICKS, that way changing
+// the frequency at runtime doesn't change the uptime that already passed.
+static UPTIME_NANOS: AtomicU64 = AtomicU64::new(0);
+static NANOS_PER_TICK: AtomicU64 = AtomicU64::new(0);
+static FREQUENCY: AtomicU32 = AtomicU32::new(0);
+// Whether the tick comes from HPET comparator 0 instead of the PIT
+static HPET_TICK: AtomicBool = AtomicBool::new(false);
+
+// The clock behind `Instant`. The counter of the clocksource is converted to nanoseconds relative to
+// the moment it became the clocksource, that way switching to a better clocksource at runtime
+// doesn't make the clock jump.
+struct Clock {
+    source: ClockSource,
+    // counter ticks per second
+    frequency: u64,
+    base_counter: u64,
+    base_nanos: u64,
+}
+static CLOCK: RwLock<Clock> = RwLock::new(Clock {
+    source: ClockSource::Pit,
+    frequency: 0,
+    base_counter: 0,
+    base_nanos: 0,
+});
+
+// Preferred in this order, the PIT is only used when nothing else is available
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockSource {
+    Tsc,
+    Hpet,
+    Pit,
+}
+impl ClockSource {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ClockSource::Tsc => "tsc",
+            ClockSource::Hpet => "hpet",
+            ClockSource::Pit => "pit",
+        }
+    }
+}
+
+static TIMER_ACTION: IrqAction = IrqAction {
+    name: "timer",
+    handler: timer_interrupt,
+};
+
+pub fn init() {
+    set_frequency(TIMER_FREQUENCY);
+    irq::register(irq::TIMER, &TIMER_ACTION).expect("timer IRQ handler already registered");
+
+    // the TSC is only a clock if its rate doesn't change with the CPU frequency
+    if tsc::is_invariant() {
+        set_clocksource(ClockSource::Tsc, tsc::calibrate_with_pit());
+    }
+}
+
+// Switches the timer subsystem to the HPET: the TSC is recalibrated against it (or the HPET becomes
+// the clocksource if there is no invariant TSC) and comparator 0 takes over the system tick from
+// the PIT. Must be called after `acpi::init` and `memory::memory::install`.
+pub fn init_hpet() -> Result<(), hpet::HpetError> {
+    let hpet = hpet::init()?;
+
+    if tsc::is_invariant() {
+        set_clocksource(ClockSource::Tsc, tsc::calibrate_with_hpet(hpet));
+    } else if hpet.is_64bit() {
+        set_clocksource(ClockSource::Hpet, hpet.frequency());
+    }
+
+    // Comparator 0 has to be running before the legacy replacement route disconnects the PIT
+    let period = hpet.frequency() / frequency().max(1) as u64;
+    hpet.periodic(0, period)?;
+    if let Err(error) = hpet.enable_legacy_replacement() {
+        hpet.disable(0);
+        return Err(error);
+    }
+    HPET_TICK.store(true, Ordering::Relaxed);
+    set_frequency(frequency());
+    Ok(())
+}
+
+// Reprograms the tick source. The actual frequency can differ slightly from the requested one,
+// because the PIT and the HPET can only divide their base frequency by an integer.
+pub fn set_frequency(frequency: u32) {
+    let frequency = frequency.max(1);
+    let nanos_per_tick = match hpet::get() {
+        Some(hpet) if HPET_TICK.load(Ordering::Relaxed) => {
+            let period = (hpet.frequency() / frequency as u64).max(1);
+            hpet.periodic(0, period).expect("HPET comparator 0 lost periodic mode");
+            (period as u128 * hpet.period_femtoseconds() as u128 / 1_000_000) as u64
+        }
+        _ => {
+            let divisor = pit::set_frequency(frequency) as u64;
+            divisor * 1_000_000_000 / pit::BASE_FREQUENCY as u64
+        }
+    };
+    NANOS_PER_TICK.store(nanos_per_tick, Ordering::Relaxed);
+    FREQUENCY.store((1_000_000_000 / nanos_per_tick) as u32, Ordering::Relaxed);
+}
+
+// Handler for IRQ 0, raised by the PIT or HPET comparator 0
+fn timer_interrupt() -> IrqResult {
+    UPTIME_NANOS.fetch_add(NANOS_PER_TICK.load(Ordering::Relaxed), Ordering::Relaxed);
+    TICKS.fetch_add(1, Ordering::Release);
+    IrqResult::Handled
+}
+
+// Number of timer interrupts since boot
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Acquire)
+}
+
+// Ticks per second, 0 until `init` was called
+pub fn frequency() -> u32 {
+    FREQUENCY.load(Ordering::Relaxed)
+}
+
+// Which timer raises the system tick, "hpet" or "pit"
+pub fn tick_source() -> &'static str {
+    if HPET_TICK.load(Ordering::Relaxed) { "hpet" } else { "pit" }
+}
+
+// Time since the tick was started, with the resolution of one tick
+pub fn uptime() -> Duration {
+    Duration::from_nanos(UPTIME_NANOS.load(Ordering::Relaxed))
+}
+
+// The clock `Instant` is based on
+pub fn clocksource() -> ClockSource {
+    CLOCK.read().source
+}
+
+// Frequency of the clocksource in Hz, that's also the resolution of `Instant`
+pub fn clocksource_frequency() -> u64 {
+    let clock = CLOCK.read();
+    match clock.source {
+        ClockSource::Pit => frequency() as u64,
+        _ => clock.frequency,
+    }
+}
+
+fn read_counter(source: ClockSource) -> u64 {
+    match source {
+        ClockSource::Tsc => tsc::read(),
+        ClockSource::Hpet => hpet::get().map_or(0, |hpet| hpet.counter()),
+        ClockSource::Pit => UPTIME_NANOS.load(Ordering::Relaxed),
+    }
+}
+
+fn set_clocksource(source: ClockSource, frequency: u64) {
+    interrupts::without_interrupts(|| {
+        let now = clock_nanos();
+        *CLOCK.write() = Clock {
+            source,
+            frequency,
+            base_counter: read_counter(source),
+            base_nanos: now,
+        };
+    });
+}
+
+// Nanoseconds of the monotonic clock
+fn clock_nanos() -> u64 {
+    let clock = CLOCK.read();
+    match clock.source {
+        ClockSource::Pit => UPTIME_NANOS.load(Ordering::Relaxed),
+        source => {
+            let elapsed = read_counter(source).wrapping_sub(clock.base_counter);
+            clock.base_nanos + (elapsed as u128 * 1_000_000_000 / clock.frequency as u128) as u64
+        }
+    }
+}
+
+// Tests
+#[test_case]
+fn test_ticks_advance() {
+    let start = ticks();
+    while ticks() == start {
+        x86_64::instructions::hlt();
+    }
+    assert!(uptime() > Duration::ZERO);
+}
+
+#[test_case]
+fn test_instant_is_monotonic() {
+    let start = Instant::now();
+    let tick = ticks();
+    while ticks() == tick {
+        x86_64::instructions::hlt();
+    }
+    let end = Instant::now();
+    assert!(end > start);
+    assert!(end - start <= end.since_boot());
+}
@@ -0,0 +1,77 @@
+// The PIT (Programmable Interval Timer, Intel 8253/8254) has an oscillator running at ~1.193182 MHz
+// and 3 channels, each with a 16 bit counter that's decremented on every oscillation:
+//
+// Channel  Port  Output
+// 0        0x40  IRQ 0, our system tick
+// 1        0x41  DRAM refresh on ancient hardware, unused
+// 2        0x42  PC speaker, the gate is controlled through bit 0 of port 0x61
+//
+// Mode/Command register (port 0x43)
+//
+// Bits  Name             Description
+// 6-7   Select channel   0-2: channel, 3: read-back command
+// 4-5   Access mode      1: low byte, 2: high byte, 3: low byte then high byte
+// 1-3   Operating mode   2: rate generator, 3: square wave generator, ...
+// 0     BCD/Binary mode  0: 16 bit binary
+//
+// The BIOS leaves channel 0 with a divisor of 0 (which means 65536), which gives us ~18.2 Hz.
+
+use x86_64::instructions::port::Port;
+use crate::sync::IrqMutex;
+
+pub const BASE_FREQUENCY: u32 = 1_193_182;
+
+const CHANNEL0: u16 = 0x40;
+const CHANNEL2: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+const CHANNEL2_GATE: u16 = 0x61;
+
+// channel 0, lobyte/hibyte, mode 2 (rate generator), binary
+const CHANNEL0_RATE_GENERATOR: u8 = 0b00_11_010_0;
+// channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count), binary
+const CHANNEL2_ONE_SHOT: u8 = 0b10_11_000_0;
+
+static LOCK: IrqMutex<()> = IrqMutex::new(());
+
+// Programs channel 0 to fire `frequency` times per second and returns the divisor that was used.
+// The frequency is clamped to what the 16 bit divisor can express (19 Hz - 1.19 MHz).
+pub fn set_frequency(frequency: u32) -> u32 {
+    let divisor = (BASE_FREQUENCY / frequency.max(1)).clamp(1, 0x10000);
+    // a divisor of 0 is interpreted as 65536
+    let reload = (divisor & 0xFFFF) as u16;
+
+    let _lock = LOCK.lock();
+    unsafe {
+        Port::<u8>::new(COMMAND).write(CHANNEL0_RATE_GENERATOR);
+        let mut data = Port::<u8>::new(CHANNEL0);
+        data.write(reload as u8);
+        data.write((reload >> 8) as u8);
+    }
+    divisor
+}
+
+// Busy waits `microseconds` (at most ~54ms) on channel 2 and returns how much `counter` advanced in
+// that time. Used to calibrate other clocks against the PIT, channel 2 doesn't raise an interrupt
+// so this works with interrupts disabled and doesn't disturb the system tick.
+pub fn measure(microseconds: u32, counter: impl Fn() -> u64) -> u64 {
+    assert!(microseconds > 0 && microseconds <= 50_000, "PIT can only measure up to 50ms");
+    let reload = (BASE_FREQUENCY as u64 * microseconds as u64 / 1_000_000) as u16;
+
+    let _lock = LOCK.lock();
+    unsafe {
+        let mut gate = Port::<u8>::new(CHANNEL2_GATE);
+        // gate high, speaker off
+        let value = gate.read();
+        gate.write((value & !0b10) | 0b1);
+
+        Port::<u8>::new(COMMAND).write(CHANNEL2_ONE_SHOT);
+        let mut data = Port::<u8>::new(CHANNEL2);
+        data.write(reload as u8);
+        data.write((reload >> 8) as u8);
+
+        let start = counter();
+        // bit 5 is the output of channel 2, it goes high once the count reached 0
+        while gate.read() & 0b10_0000 == 0 {}
+        counter() - start
+    }
+}
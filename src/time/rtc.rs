@@ -0,0 +1,372 @@
+// The CMOS RTC (Real Time Clock) keeps the date and time while the machine is off. Its registers
+// are accessed by writing the register index to port 0x70 and then reading or writing port 0x71.
+//
+// Register  Name       Description
+// 0x00      Seconds
+// 0x01      Seconds    alarm
+// 0x02      Minutes
+// 0x03      Minutes    alarm
+// 0x04      Hours      bit 7 is set for PM in 12 hour mode
+// 0x05      Hours      alarm
+// 0x06      Weekday    1-7, Sunday is 1
+// 0x07      Day        1-31
+// 0x08      Month      1-12
+// 0x09      Year       last two digits
+// 0x0A      Status A   bit 7: update in progress, bits 0-3: periodic interrupt rate
+// 0x0B      Status B   bit 1: 24 hour mode, bit 2: binary instead of BCD, bit 4: update ended
+//                      interrupt, bit 5: alarm interrupt, bit 6: periodic interrupt, bit 7: halt
+//                      updates
+// 0x0C      Status C   which interrupt fired, reading it acknowledges the interrupt
+// (FADT)    Century    the index is in the FADT, 0 if there is no century register
+// 0x70      Keymap     not an RTC register, the saved keyboard layout, see task::keymap
+//
+// The RTC updates its registers once per second. While the update is in progress (and a bit before
+// it, the flag is set 244us early) the values might be inconsistent, so we wait for the flag to
+// clear and read everything twice until both readings match.
+//
+// The RTC raises IRQ 8, except when the HPET runs in legacy replacement mode: then IRQ 8 belongs to
+// HPET comparator 1.
+
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    task::{Context, Poll},
+};
+use futures_util::task::AtomicWaker;
+use x86_64::instructions::port::Port;
+use crate::{acpi::fadt, memory::irq::{self, IrqAction, IrqError, IrqResult}, sync::IrqMutex};
+use super::{hpet, system_time::DateTime};
+
+const SECONDS: u8 = 0x00;
+const SECONDS_ALARM: u8 = 0x01;
+const MINUTES: u8 = 0x02;
+const MINUTES_ALARM: u8 = 0x03;
+const HOURS: u8 = 0x04;
+const HOURS_ALARM: u8 = 0x05;
+const WEEKDAY: u8 = 0x06;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0A;
+const STATUS_B: u8 = 0x0B;
+const STATUS_C: u8 = 0x0C;
+
+// Status A
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const RATE_MASK: u8 = 0b1111;
+// Status B
+const HOUR_24: u8 = 1 << 1;
+const BINARY: u8 = 1 << 2;
+const ALARM_INTERRUPT: u8 = 1 << 5;
+const PERIODIC_INTERRUPT: u8 = 1 << 6;
+const HALT_UPDATES: u8 = 1 << 7;
+// Status C, same bits as the enables in Status B
+const INTERRUPT_REQUEST_FLAG: u8 = 1 << 7;
+const ALARM_FLAG: u8 = 1 << 5;
+const PERIODIC_FLAG: u8 = 1 << 6;
+
+// The index and data port have to be used as a pair, the lock keeps another register access from
+// selecting a different register in between. The interrupt handler needs it too, IrqMutex keeps
+// it from interrupting a holder.
+static CMOS: IrqMutex<Cmos> = IrqMutex::new(Cmos::new());
+
+static PERIODIC_COUNT: AtomicU64 = AtomicU64::new(0);
+static ALARM_FIRED: AtomicBool = AtomicBool::new(false);
+static ALARM_WAKER: AtomicWaker = AtomicWaker::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RtcError {
+    InvalidDate,
+    InvalidRate,
+    // IRQ 8 is routed to the HPET
+    IrqUnavailable,
+}
+
+struct Cmos {
+    index: Port<u8>,
+    data: Port<u8>,
+}
+impl Cmos {
+    const fn new() -> Self {
+        Cmos {
+            index: Port::new(0x70),
+            data: Port::new(0x71),
+        }
+    }
+
+    fn read(&mut self, register: u8) -> u8 {
+        unsafe {
+            self.index.write(register);
+            self.data.read()
+        }
+    }
+
+    fn write(&mut self, register: u8, value: u8) {
+        unsafe {
+            self.index.write(register);
+            self.data.write(value);
+        }
+    }
+
+    // The raw register values, in whatever format status B says
+    fn read_raw(&mut self, century: u8) -> [u8; 7] {
+        while self.read(STATUS_A) & UPDATE_IN_PROGRESS != 0 {}
+        [
+            self.read(SECONDS),
+            self.read(MINUTES),
+            self.read(HOURS),
+            self.read(DAY),
+            self.read(MONTH),
+            self.read(YEAR),
+            if century != 0 { self.read(century) } else { 0 },
+        ]
+    }
+}
+
+fn century_register() -> u8 {
+    fadt::get().map_or(0, |fadt| fadt.century)
+}
+
+pub fn read() -> DateTime {
+    let century = century_register();
+    let (raw, status_b) = {
+        let mut cmos = CMOS.lock();
+        let mut raw = cmos.read_raw(century);
+        loop {
+            let again = cmos.read_raw(century);
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+        (raw, cmos.read(STATUS_B))
+    };
+
+    let decode = |value: u8| if status_b & BINARY != 0 { value } else { from_bcd(value) };
+    let [second, minute, hour, day, month, year, century_value] = raw;
+
+    let mut hour_24 = decode(hour & 0x7F);
+    if status_b & HOUR_24 == 0 {
+        // 12 AM is midnight, 12 PM is noon
+        hour_24 %= 12;
+        if hour & 0x80 != 0 {
+            hour_24 += 12;
+        }
+    }
+
+    let year = decode(year) as u16;
+    let century = if century != 0 {
+        decode(century_value) as u16
+    } else if year < 70 {
+        20
+    } else {
+        19
+    };
+
+    DateTime {
+        year: century * 100 + year,
+        month: decode(month),
+        day: decode(day),
+        hour: hour_24,
+        minute: decode(minute),
+        second: decode(second),
+    }
+}
+
+// Sets the RTC, keeping its BCD/binary and 12/24 hour format
+pub fn write(datetime: DateTime) -> Result<(), RtcError> {
+    let century = century_register();
+    if !datetime.is_valid() || (century == 0 && !(1970..2070).contains(&datetime.year)) {
+        return Err(RtcError::InvalidDate);
+    }
+
+    let mut cmos = CMOS.lock();
+    let status_b = cmos.read(STATUS_B);
+    let encode = |value: u8| encode(status_b, value);
+
+    // stop the updates so the RTC doesn't tick in the middle of our writes
+    cmos.write(STATUS_B, status_b | HALT_UPDATES);
+    cmos.write(SECONDS, encode(datetime.second));
+    cmos.write(MINUTES, encode(datetime.minute));
+    cmos.write(HOURS, encode_hour(status_b, datetime.hour));
+    cmos.write(WEEKDAY, encode(datetime.weekday() + 1));
+    cmos.write(DAY, encode(datetime.day));
+    cmos.write(MONTH, encode(datetime.month));
+    cmos.write(YEAR, encode((datetime.year % 100) as u8));
+    if century != 0 {
+        cmos.write(century, encode((datetime.year / 100) as u8));
+    }
+    cmos.write(STATUS_B, status_b & !HALT_UPDATES);
+    Ok(())
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0F)
+}
+
+// Converts `value` to the format selected in status B
+fn encode(status_b: u8, value: u8) -> u8 {
+    if status_b & BINARY != 0 {
+        value
+    } else {
+        ((value / 10) << 4) | (value % 10)
+    }
+}
+
+fn encode_hour(status_b: u8, hour: u8) -> u8 {
+    if status_b & HOUR_24 != 0 {
+        return encode(status_b, hour);
+    }
+    let hour_12 = match hour % 12 {
+        0 => 12,
+        hour => hour,
+    };
+    encode(status_b, hour_12) | if hour >= 12 { 0x80 } else { 0 }
+}
+
+// Interrupts
+
+static RTC_ACTION: IrqAction = IrqAction {
+    name: "rtc",
+    handler: rtc_interrupt,
+};
+
+// Handler for IRQ 8
+fn rtc_interrupt() -> IrqResult {
+    // until status C is read, the RTC won't raise another interrupt
+    let flags = CMOS.lock().read(STATUS_C);
+    if flags & INTERRUPT_REQUEST_FLAG == 0 {
+        return IrqResult::NotMine;
+    }
+    if flags & PERIODIC_FLAG != 0 {
+        PERIODIC_COUNT.fetch_add(1, Ordering::Relaxed);
+    }
+    if flags & ALARM_FLAG != 0 {
+        ALARM_FIRED.store(true, Ordering::Release);
+        ALARM_WAKER.wake();
+    }
+    IrqResult::Handled
+}
+
+fn enable_interrupt(enable: u8) -> Result<(), RtcError> {
+    if hpet::get().map_or(false, |hpet| hpet.legacy_replacement_enabled()) {
+        return Err(RtcError::IrqUnavailable);
+    }
+
+    {
+        let mut cmos = CMOS.lock();
+        let status_b = cmos.read(STATUS_B);
+        cmos.write(STATUS_B, status_b | enable);
+        // an interrupt that fired before we were listening would block all further ones
+        cmos.read(STATUS_C);
+    }
+
+    match irq::register(irq::RTC, &RTC_ACTION) {
+        Ok(()) | Err(IrqError::AlreadyRegistered) => Ok(()),
+        Err(_) => Err(RtcError::IrqUnavailable),
+    }
+}
+
+fn disable_interrupt(enable: u8) {
+    let status_b = {
+        let mut cmos = CMOS.lock();
+        let status_b = cmos.read(STATUS_B) & !enable;
+        cmos.write(STATUS_B, status_b);
+        status_b
+    };
+    if status_b & (ALARM_INTERRUPT | PERIODIC_INTERRUPT) == 0 {
+        let _ = irq::unregister(irq::RTC, &RTC_ACTION);
+    }
+}
+
+// Raises IRQ 8 at 32768 >> (rate - 1) Hz, from 2 Hz (rate 15) to 8192 Hz (rate 3). Returns the
+// frequency.
+pub fn enable_periodic(rate: u8) -> Result<u32, RtcError> {
+    if !(3..=15).contains(&rate) {
+        return Err(RtcError::InvalidRate);
+    }
+    {
+        let mut cmos = CMOS.lock();
+        let status_a = cmos.read(STATUS_A);
+        cmos.write(STATUS_A, (status_a & !RATE_MASK) | rate);
+    }
+    enable_interrupt(PERIODIC_INTERRUPT)?;
+    Ok(32768 >> (rate - 1))
+}
+
+pub fn disable_periodic() {
+    disable_interrupt(PERIODIC_INTERRUPT);
+}
+
+// Number of periodic interrupts since boot
+pub fn periodic_count() -> u64 {
+    PERIODIC_COUNT.load(Ordering::Relaxed)
+}
+
+// Battery backed CMOS bytes the kernel keeps its own settings in. They have to be past the RTC
+// registers and the BIOS area (0x0E-0x3F), and below 0x80: the top bit of the index port disables
+// NMIs. The BIOS, QEMU and SeaBIOS leave 0x70-0x7F alone.
+pub const KEYMAP_SETTING: u8 = 0x70;
+
+pub fn read_setting(register: u8) -> u8 {
+    assert!((0x40..0x80).contains(&register), "CMOS register {:#x} isn't a setting", register);
+    CMOS.lock().read(register)
+}
+
+pub fn write_setting(register: u8, value: u8) {
+    assert!((0x40..0x80).contains(&register), "CMOS register {:#x} isn't a setting", register);
+    CMOS.lock().write(register, value);
+}
+
+// Raises IRQ 8 once the RTC reaches the given time of day. The returned future completes when the
+// alarm went off.
+pub fn set_alarm(hour: u8, minute: u8, second: u8) -> Result<Alarm, RtcError> {
+    if hour >= 24 || minute >= 60 || second >= 60 {
+        return Err(RtcError::InvalidDate);
+    }
+
+    {
+        let mut cmos = CMOS.lock();
+        let status_b = cmos.read(STATUS_B);
+        cmos.write(SECONDS_ALARM, encode(status_b, second));
+        cmos.write(MINUTES_ALARM, encode(status_b, minute));
+        cmos.write(HOURS_ALARM, encode_hour(status_b, hour));
+    }
+    ALARM_FIRED.store(false, Ordering::Release);
+    enable_interrupt(ALARM_INTERRUPT)?;
+    Ok(Alarm { _private: () })
+}
+
+pub fn clear_alarm() {
+    disable_interrupt(ALARM_INTERRUPT);
+}
+
+pub struct Alarm {
+    _private: (),
+}
+impl Future for Alarm {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<()> {
+        if ALARM_FIRED.swap(false, Ordering::AcqRel) {
+            return Poll::Ready(());
+        }
+
+        ALARM_WAKER.register(context.waker());
+        if ALARM_FIRED.swap(false, Ordering::AcqRel) {
+            ALARM_WAKER.take();
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+// Tests
+#[test_case]
+fn test_rtc_reads_valid_date() {
+    let datetime = read();
+    assert!(datetime.is_valid());
+    assert!(datetime.year >= 2000);
+}
@@ -0,0 +1,217 @@
+use core::{
+    fmt,
+    ops::{Add, Sub},
+    time::Duration,
+};
+use crate::sync::IrqMutex;
+use super::{rtc, Instant};
+
+// Wall-clock time, measured as nanoseconds since 1970-01-01 00:00:00. Unlike `Instant` it can jump
+// when the time is set, so use `Instant` to measure durations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SystemTime(u64);
+
+pub const UNIX_EPOCH: SystemTime = SystemTime(0);
+
+// Reading the RTC takes up to a second (it only has a resolution of one second and we might have
+// to wait for an update to finish), so we read it once and count from there with the monotonic
+// clock. The pair is the wall-clock time at that instant.
+static WALL_CLOCK: IrqMutex<Option<(SystemTime, Instant)>> = IrqMutex::new(None);
+
+// Reads the RTC. Must be called after `acpi::fadt::init`, the century register is in the FADT.
+pub fn init() {
+    let now = SystemTime::from_datetime(rtc::read());
+    *WALL_CLOCK.lock() = Some((now, Instant::now()));
+}
+
+// Sets the RTC and the system time
+pub fn set(datetime: DateTime) -> Result<(), rtc::RtcError> {
+    rtc::write(datetime)?;
+    *WALL_CLOCK.lock() = Some((SystemTime::from_datetime(datetime), Instant::now()));
+    Ok(())
+}
+
+impl SystemTime {
+    pub fn now() -> SystemTime {
+        let wall_clock = *WALL_CLOCK.lock();
+        let (time, instant) = match wall_clock {
+            Some(wall_clock) => wall_clock,
+            None => {
+                init();
+                WALL_CLOCK.lock().expect("wall clock initialized above")
+            }
+        };
+        time + instant.elapsed()
+    }
+
+    // Err contains how much later than `self` `earlier` is
+    pub fn duration_since(&self, earlier: SystemTime) -> Result<Duration, Duration> {
+        if self.0 >= earlier.0 {
+            Ok(Duration::from_nanos(self.0 - earlier.0))
+        } else {
+            Err(Duration::from_nanos(earlier.0 - self.0))
+        }
+    }
+
+    pub fn elapsed(&self) -> Result<Duration, Duration> {
+        SystemTime::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<SystemTime> {
+        u64::try_from(duration.as_nanos()).ok()
+            .and_then(|nanos| self.0.checked_add(nanos))
+            .map(SystemTime)
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<SystemTime> {
+        u64::try_from(duration.as_nanos()).ok()
+            .and_then(|nanos| self.0.checked_sub(nanos))
+            .map(SystemTime)
+    }
+
+    // Dates before 1970 are clamped to the epoch
+    pub fn from_datetime(datetime: DateTime) -> SystemTime {
+        let days = days_from_civil(datetime.year as i64, datetime.month as i64, datetime.day as i64);
+        let seconds = days * 86400
+            + datetime.hour as i64 * 3600
+            + datetime.minute as i64 * 60
+            + datetime.second as i64;
+        SystemTime(seconds.max(0) as u64 * 1_000_000_000)
+    }
+
+    pub fn to_datetime(&self) -> DateTime {
+        let seconds = self.0 / 1_000_000_000;
+        let (year, month, day) = civil_from_days((seconds / 86400) as i64);
+        let seconds_of_day = seconds % 86400;
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (seconds_of_day / 3600) as u8,
+            minute: (seconds_of_day / 60 % 60) as u8,
+            second: (seconds_of_day % 60) as u8,
+        }
+    }
+}
+
+impl Add<Duration> for SystemTime {
+    type Output = SystemTime;
+
+    fn add(self, duration: Duration) -> SystemTime {
+        self.checked_add(duration).expect("overflow when adding duration to system time")
+    }
+}
+impl Sub<Duration> for SystemTime {
+    type Output = SystemTime;
+
+    fn sub(self, duration: Duration) -> SystemTime {
+        self.checked_sub(duration).expect("overflow when subtracting duration from system time")
+    }
+}
+
+// A calendar date and time of day, in whatever time zone the RTC is set to (usually UTC)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+impl DateTime {
+    // Parses "YYYY-MM-DD" and "HH:MM:SS"
+    pub fn parse(date: &str, time: &str) -> Option<DateTime> {
+        let mut date = date.split('-');
+        let mut time = time.split(':');
+        let datetime = DateTime {
+            year: date.next()?.parse().ok()?,
+            month: date.next()?.parse().ok()?,
+            day: date.next()?.parse().ok()?,
+            hour: time.next()?.parse().ok()?,
+            minute: time.next()?.parse().ok()?,
+            second: time.next()?.parse().ok()?,
+        };
+        if date.next().is_some() || time.next().is_some() || !datetime.is_valid() {
+            return None;
+        }
+        Some(datetime)
+    }
+
+    pub fn is_valid(&self) -> bool {
+        (1..=12).contains(&self.month)
+            && self.day >= 1
+            && self.day <= days_in_month(self.year, self.month)
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+    }
+
+    // 0 is Sunday
+    pub fn weekday(&self) -> u8 {
+        let days = days_from_civil(self.year as i64, self.month as i64, self.day as i64);
+        // 1970-01-01 was a Thursday
+        (days + 4).rem_euclid(7) as u8
+    }
+}
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second,
+        )
+    }
+}
+
+fn is_leap_year(year: u16) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// Days since 1970-01-01 of a date in the proleptic Gregorian calendar. The year is shifted to start
+// in March, that way the leap day is the last day of the year. An era is a 400 year cycle, after
+// which the calendar repeats itself.
+// See http://howardhinnant.github.io/date_algorithms.html
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+// Inverse of `days_from_civil`
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400;
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
+
+// Tests
+#[test_case]
+fn test_datetime_roundtrip() {
+    let datetime = DateTime { year: 2024, month: 2, day: 29, hour: 23, minute: 59, second: 58 };
+    let time = SystemTime::from_datetime(datetime);
+    assert_eq!(time.duration_since(UNIX_EPOCH), Ok(Duration::from_secs(1709251198)));
+    assert_eq!(time.to_datetime(), datetime);
+    assert_eq!(datetime.weekday(), 4);
+    assert_eq!(DateTime::parse("2024-02-29", "23:59:58"), Some(datetime));
+    assert_eq!(DateTime::parse("2023-02-29", "23:59:58"), None);
+}
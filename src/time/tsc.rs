@@ -0,0 +1,68 @@
+// The TSC (Time Stamp Counter) is a 64 bit counter that's incremented every CPU cycle and can be
+// read with a single instruction, which makes it the cheapest and most precise clock we have.
+//
+// On old CPUs the TSC rate changes with the CPU frequency (P-states) and stops in deep sleep
+// (C-states), so it's only usable as a clock when CPUID reports it as invariant, see
+// `cpu::Feature::InvariantTsc`.
+//
+// The TSC frequency isn't reported reliably by CPUID, so we measure it at boot against a clock
+// with a known frequency.
+
+use core::arch::asm;
+use crate::cpu::{self, Feature};
+use super::{hpet::Hpet, pit};
+
+// Runs at a constant rate in all P-, C- and T-states
+pub fn is_invariant() -> bool {
+    cpu::has(Feature::InvariantTsc)
+}
+
+pub fn read() -> u64 {
+    let (low, high): (u32, u32);
+    unsafe {
+        asm!("rdtsc", out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags));
+    }
+    ((high as u64) << 32) | low as u64
+}
+
+// Measures the TSC frequency in Hz against PIT channel 2. We take the smallest of a few 10ms
+// samples, an interrupt or SMI during a sample can only make it longer.
+pub fn calibrate_with_pit() -> u64 {
+    const SAMPLE_MICROSECONDS: u32 = 10_000;
+
+    let cycles = (0..3)
+        .map(|_| pit::measure(SAMPLE_MICROSECONDS, read))
+        .min()
+        .unwrap();
+    cycles * (1_000_000 / SAMPLE_MICROSECONDS as u64)
+}
+
+// Measures the TSC frequency in Hz against the HPET. Both counters are read at the start and the
+// end of the sample, so unlike with the PIT an interrupt during the sample doesn't skew the result.
+pub fn calibrate_with_hpet(hpet: &Hpet) -> u64 {
+    let window = hpet.frequency() / 100; // 10ms
+    let elapsed = |start| counter_delta(start, hpet.counter(), hpet.is_64bit());
+
+    let hpet_start = hpet.counter();
+    let tsc_start = read();
+    while elapsed(hpet_start) < window {}
+    let cycles = read() - tsc_start;
+    let elapsed = elapsed(hpet_start);
+
+    (cycles as u128 * hpet.frequency() as u128 / elapsed as u128) as u64
+}
+
+// HPET ticks from `start` to `end`. A 32 bit counter wraps every few minutes, possibly during the
+// sample, and the upper half of what we read from it means nothing.
+fn counter_delta(start: u64, end: u64, is_64bit: bool) -> u64 {
+    let delta = end.wrapping_sub(start);
+    if is_64bit { delta } else { delta & u32::MAX as u64 }
+}
+
+// Tests
+#[test_case]
+fn test_counter_delta() {
+    assert_eq!(counter_delta(0xFFFF_FFF0, 0x10, false), 0x20);
+    assert_eq!(counter_delta(0xDEAD_0000_FFFF_FFF0, 0x10, false), 0x20);
+    assert_eq!(counter_delta(0xFFFF_FFF0, 0x1_0000_0010, true), 0x20);
+}
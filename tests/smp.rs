@@ -0,0 +1,53 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(cometos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use cometos::{
+    memory::{allocator, memory::{self, BootInfoFrameAllocator}},
+    smp::{self, CpuState},
+};
+use core::panic::PanicInfo;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    cometos::init();
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = memory::init(physical_memory_offset);
+    let mut frame_allocator = BootInfoFrameAllocator::init(&boot_info.memory_map);
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap init failed");
+    cometos::acpi::init().expect("ACPI init failed");
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+// QEMU runs the tests with `-smp 4`, see test-args in Cargo.toml
+#[test_case]
+fn test_start_application_processors() {
+    assert_eq!(smp::init().expect("SMP init failed"), 4);
+
+    let cpus: alloc::vec::Vec<_> = smp::cpus().collect();
+    assert_eq!(cpus.len(), 4);
+    assert!(cpus.iter().all(|cpu| cpu.state() == CpuState::Online));
+    for (i, cpu) in cpus.iter().enumerate() {
+        assert_eq!(cpu.index(), i);
+        assert!(cpus[..i].iter().all(|other| other.apic_id() != cpu.apic_id()));
+    }
+
+    // we are still on the BSP
+    assert!(smp::current().is_bootstrap());
+    assert_eq!(smp::current().apic_id(), smp::apic::id());
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    cometos::test_panic_handler(info)
+}
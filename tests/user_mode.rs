@@ -0,0 +1,61 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(cometos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use cometos::memory::{allocator, memory::{self, BootInfoFrameAllocator}, usermode};
+use core::panic::PanicInfo;
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    cometos::init();
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = memory::init(physical_memory_offset);
+    let mut frame_allocator = BootInfoFrameAllocator::init(&boot_info.memory_map);
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap init failed");
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+// Far away from the kernel, the heap and the physical memory mapping
+const USER_CODE: u64 = 0x0000_7000_0000_0000;
+const USER_STACK: u64 = USER_CODE + 0x10_0000;
+
+// mov eax, 1 (uptime); syscall; mov edi, eax; mov eax, 2 (exit); int 0x80; jmp $
+const PROGRAM: [u8; 18] = [
+    0xB8, 0x01, 0x00, 0x00, 0x00,
+    0x0F, 0x05,
+    0x89, 0xC7,
+    0xB8, 0x02, 0x00, 0x00, 0x00,
+    0xCD, 0x80,
+    0xEB, 0xFE,
+];
+
+#[test_case]
+fn test_run_user() {
+    memory::map_user(VirtAddr::new(USER_CODE), 4096, PageTableFlags::WRITABLE).expect("mapping user code failed");
+    memory::map_user(VirtAddr::new(USER_STACK - 4096), 4096, PageTableFlags::WRITABLE).expect("mapping user stack failed");
+    unsafe {
+        core::ptr::copy_nonoverlapping(PROGRAM.as_ptr(), USER_CODE as *mut u8, PROGRAM.len());
+    }
+
+    // the program exits with the low half of the uptime, which went through SYSCALL and SYSRET
+    let before = cometos::time::uptime().as_nanos() as u32;
+    let code = unsafe { usermode::run_user(VirtAddr::new(USER_CODE), VirtAddr::new(USER_STACK)) };
+    assert!(!usermode::is_running());
+    // a little later than `before`, modulo the wrap of the u32
+    assert!((code as u32).wrapping_sub(before) < u32::MAX / 2);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    cometos::test_panic_handler(info)
+}
@@ -0,0 +1,52 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(cometos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use cometos::{
+    memory::{allocator, memory::{self, BootInfoFrameAllocator}},
+    smp::{self, watchdog},
+};
+use core::{hint::spin_loop, panic::PanicInfo, time::Duration};
+use x86_64::{instructions::interrupts, VirtAddr};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    cometos::init();
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = memory::init(physical_memory_offset);
+    let mut frame_allocator = BootInfoFrameAllocator::init(&boot_info.memory_map);
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap init failed");
+    cometos::acpi::init().expect("ACPI init failed");
+    memory::install(mapper, frame_allocator);
+    smp::init().expect("SMP init failed");
+
+    test_main();
+    loop {}
+}
+
+#[test_case]
+fn test_lockup_is_reported() {
+    watchdog::init(Duration::from_millis(500)).expect("watchdog init failed");
+    while !watchdog::is_running() {
+        spin_loop();
+    }
+
+    // the NMI gets through even though the timer interrupt doesn't
+    interrupts::without_interrupts(|| {
+        while watchdog::reports() == 0 {
+            spin_loop();
+        }
+    });
+    assert_eq!(watchdog::reports(), 1);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    cometos::test_panic_handler(info)
+}